@@ -0,0 +1,163 @@
+//! Fixed-point money type. Amounts are stored as an `i64` scaled by 10^4 so
+//! every value with up to four decimal places is represented exactly, and
+//! balances never drift the way they would with floating point.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Number of decimal places we keep track of.
+pub const DECIMALS: u32 = 4;
+/// Scaling factor between the raw representation and one unit.
+pub const SCALE: i64 = 10_i64.pow(DECIMALS);
+
+/// An exact decimal amount with four digits past the decimal point.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    /// Builds an [`Amount`] from its raw representation, in ten-thousandths
+    /// of a unit.
+    pub const fn from_raw(raw: i64) -> Self {
+        Amount(raw)
+    }
+
+    /// Returns the raw representation, in ten-thousandths of a unit.
+    pub const fn raw(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Returns the sum of two amounts or None on overflow.
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    /// Returns the difference of two amounts or None on overflow.
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Parses an amount straight from the bytes of a csv field, without
+    /// going through a float. Accepts an optional sign, an integer part and
+    /// up to four decimal places (extra trailing zeros are fine). Returns
+    /// None for anything else, including values that would overflow.
+    pub fn from_bytes(x: &[u8]) -> Option<Self> {
+        let (negative, digits) = match x.split_first() {
+            Some((b'-', rest)) => (true, rest),
+            Some((b'+', rest)) => (false, rest),
+            _ => (false, x),
+        };
+
+        let (int_part, frac_part) = match digits.iter().position(|&b| b == b'.') {
+            Some(i) => (&digits[..i], &digits[i + 1..]),
+            None => (digits, &[][..]),
+        };
+
+        if int_part.is_empty() && frac_part.is_empty() {
+            return None;
+        }
+
+        let mut raw: i64 = 0;
+        for &b in int_part {
+            if !b.is_ascii_digit() {
+                return None;
+            }
+            raw = raw.checked_mul(10)?.checked_add((b - b'0') as i64)?;
+        }
+        raw = raw.checked_mul(SCALE)?;
+
+        let mut unit = SCALE;
+        for &b in frac_part {
+            if !b.is_ascii_digit() {
+                return None;
+            }
+            unit /= 10;
+            if unit == 0 {
+                // Past the fourth decimal place only zeros are accepted,
+                // we never round silently.
+                if b != b'0' {
+                    return None;
+                }
+            } else {
+                raw = raw.checked_add((b - b'0') as i64 * unit)?;
+            }
+        }
+
+        Some(Amount(if negative { -raw } else { raw }))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Amount::from_bytes(s.as_bytes()).ok_or(())
+    }
+}
+
+impl fmt::Display for Amount {
+    /// Always prints the four decimal places. Width and alignment flags are
+    /// honoured, precision is not.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let abs = self.0.unsigned_abs();
+        let scale = SCALE as u64;
+        let s = format!("{}.{:04}", abs / scale, abs % scale);
+        f.pad_integral(self.0 >= 0, "", &s)
+    }
+}
+
+#[test]
+fn test_amount_parsing() {
+    assert_eq!(Amount::from_bytes(b"1"), Some(Amount(10_000)));
+    assert_eq!(Amount::from_bytes(b"1.5"), Some(Amount(15_000)));
+    assert_eq!(Amount::from_bytes(b"0.0001"), Some(Amount(1)));
+    assert_eq!(Amount::from_bytes(b".25"), Some(Amount(2_500)));
+    assert_eq!(Amount::from_bytes(b"2."), Some(Amount(20_000)));
+    assert_eq!(Amount::from_bytes(b"-3.1"), Some(Amount(-31_000)));
+    assert_eq!(Amount::from_bytes(b"1.230000"), Some(Amount(12_300)));
+}
+
+#[test]
+fn test_amount_bad_parsing() {
+    assert_eq!(Amount::from_bytes(b""), None);
+    assert_eq!(Amount::from_bytes(b"-"), None);
+    assert_eq!(Amount::from_bytes(b"."), None);
+    assert_eq!(Amount::from_bytes(b"1.00001"), None);
+    assert_eq!(Amount::from_bytes(b"1e3"), None);
+    assert_eq!(Amount::from_bytes(b"1.2.3"), None);
+    assert_eq!(Amount::from_bytes(b"99999999999999999999"), None);
+}
+
+#[test]
+fn test_amount_exact_sum() {
+    // 0.1 added ten thousand times drifts with f32, not here.
+    let step = Amount::from_bytes(b"0.1").unwrap();
+    let mut sum = Amount::ZERO;
+    for _ in 0..10_000 {
+        sum = sum.checked_add(step).unwrap();
+    }
+    assert_eq!(sum, Amount::from_bytes(b"1000").unwrap());
+}
+
+#[test]
+fn test_amount_checked_overflow() {
+    let max = Amount::from_raw(i64::MAX);
+    assert_eq!(max.checked_add(Amount::from_raw(1)), None);
+    assert_eq!(
+        Amount::from_raw(i64::MIN).checked_sub(Amount::from_raw(1)),
+        None
+    );
+}
+
+#[test]
+fn test_amount_display() {
+    assert_eq!(Amount::from_bytes(b"1.5").unwrap().to_string(), "1.5000");
+    assert_eq!(Amount::from_bytes(b"-0.25").unwrap().to_string(), "-0.2500");
+    assert_eq!(Amount::ZERO.to_string(), "0.0000");
+    assert_eq!(format!("{:>9}", Amount::from_raw(10_000)), "   1.0000");
+}
@@ -0,0 +1,244 @@
+//! Column resolution. The columns of an input are found by their headers,
+//! whatever their order, casing or the extra columns around them, or taken
+//! in the canonical order when there is no header row. A first row naming
+//! none of the columns is no header row.
+//!
+//! Only transfers need the `destination` column, an input lacking it is fine
+//! as long as it holds no transfer.
+
+use super::error::{EngineError, EngineErrorKind, Result};
+use csv::ByteRecord;
+use std::io;
+
+/// A column transaction rows are made of.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Column {
+    Type,
+    Client,
+    Tx,
+    Amount,
+    /// The client credited by a transfer.
+    Destination,
+}
+
+impl Column {
+    /// Every column, in the canonical order.
+    pub const ALL: [Column; 5] = [
+        Column::Type,
+        Column::Client,
+        Column::Tx,
+        Column::Amount,
+        Column::Destination,
+    ];
+
+    /// Returns a column from its canonical header, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "type" => Some(Self::Type),
+            "client" => Some(Self::Client),
+            "tx" => Some(Self::Tx),
+            "amount" => Some(Self::Amount),
+            "destination" => Some(Self::Destination),
+            _ => None,
+        }
+    }
+
+    /// The canonical header of the column.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Type => "type",
+            Self::Client => "client",
+            Self::Tx => "tx",
+            Self::Amount => "amount",
+            Self::Destination => "destination",
+        }
+    }
+
+    /// The column a header stands for, by its canonical name or by one of
+    /// the `aliases`, if any.
+    fn from_header(header: &[u8], aliases: &[(String, Column)]) -> Option<Self> {
+        Column::ALL
+            .into_iter()
+            .find(|column| header_matches(header, column.name()))
+            .or_else(|| {
+                aliases
+                    .iter()
+                    .find(|(alias, _)| header_matches(header, alias))
+                    .map(|(_, column)| *column)
+            })
+    }
+
+    /// Whether every input needs this column.
+    pub fn is_required(self) -> bool {
+        self != Self::Destination
+    }
+}
+
+/// Where each column sits in the rows of an input. The default one is the
+/// canonical `type,client,tx,amount,destination` order.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ColumnMap {
+    indices: [Option<usize>; 5],
+}
+
+impl Default for ColumnMap {
+    fn default() -> Self {
+        Self {
+            indices: [Some(0), Some(1), Some(2), Some(3), Some(4)],
+        }
+    }
+}
+
+impl ColumnMap {
+    /// Finds the columns in the header row of an input. A header goes by
+    /// the canonical name of its column or by one of its `aliases`, both
+    /// compared regardless of casing and surrounding whitespaces. Unknown
+    /// columns are ignored, and the first one wins when a column shows up
+    /// twice. Fails with the required columns missing.
+    pub fn from_headers(headers: &ByteRecord, aliases: &[(String, Column)]) -> Result<Self> {
+        let mut indices = [None; 5];
+        for (i, header) in headers.iter().enumerate() {
+            if let Some(column) = Column::from_header(header, aliases) {
+                indices[column as usize].get_or_insert(i);
+            }
+        }
+
+        let missing: Vec<_> = Column::ALL
+            .into_iter()
+            .filter(|column| column.is_required() && indices[*column as usize].is_none())
+            .map(|column| column.name().to_string())
+            .collect();
+        if !missing.is_empty() {
+            return Err(EngineError::new(EngineErrorKind::InvalidHeaders(missing)));
+        }
+        Ok(Self { indices })
+    }
+
+    /// Index of `column` in the rows, if the input has it.
+    pub fn index(&self, column: Column) -> Option<usize> {
+        self.indices[column as usize]
+    }
+
+    /// The field of `row` holding `column`, if the row goes that far.
+    pub fn get<'r>(&self, row: &'r ByteRecord, column: Column) -> Option<&'r [u8]> {
+        row.get(self.index(column)?)
+    }
+}
+
+/// The rows of a csv input, along with where their columns are.
+pub(crate) struct Rows<R> {
+    rdr: csv::Reader<R>,
+    pub columns: ColumnMap,
+    /// The header row, if the input has one.
+    pub headers: Option<ByteRecord>,
+    /// The first row, when it's not a header row and is still to be read.
+    first: Option<ByteRecord>,
+}
+
+impl<R: io::Read> Rows<R> {
+    /// Rows of an input with a header row, `first`.
+    pub fn with_headers(rdr: csv::Reader<R>, first: ByteRecord, columns: ColumnMap) -> Self {
+        Self {
+            rdr,
+            columns,
+            headers: Some(first),
+            first: None,
+        }
+    }
+
+    /// Rows of an input without a header row, starting with `first`.
+    pub fn without_headers(rdr: csv::Reader<R>, first: Option<ByteRecord>) -> Self {
+        Self {
+            rdr,
+            columns: ColumnMap::default(),
+            headers: None,
+            first,
+        }
+    }
+
+    /// Reads the next row into `row`, false meaning the input is over.
+    pub fn read(&mut self, row: &mut ByteRecord) -> csv::Result<bool> {
+        match self.first.take() {
+            Some(first) => {
+                *row = first;
+                Ok(true)
+            }
+            None => self.rdr.read_byte_record(row),
+        }
+    }
+}
+
+/// Whether `row` names at least one known column, by its canonical name or
+/// by one of the `aliases`, and so may be taken for a header row.
+pub(crate) fn names_columns(row: &ByteRecord, aliases: &[(String, Column)]) -> bool {
+    row.iter()
+        .any(|field| Column::from_header(field, aliases).is_some())
+}
+
+/// Whether a header stands for `name`, regardless of casing, surrounding
+/// whitespaces and a leading byte order mark.
+pub(crate) fn header_matches(header: &[u8], name: &str) -> bool {
+    let header = header.strip_prefix("\u{feff}".as_bytes()).unwrap_or(header);
+    header
+        .trim_ascii()
+        .eq_ignore_ascii_case(name.trim().as_bytes())
+}
+
+#[test]
+fn test_column_map_from_headers() {
+    let headers = ByteRecord::from(vec!["\u{feff}TX", " Client ", "note", "type", "Amount"]);
+    let columns = ColumnMap::from_headers(&headers, &[]).unwrap();
+    assert_eq!(columns.index(Column::Type), Some(3));
+    assert_eq!(columns.index(Column::Client), Some(1));
+    assert_eq!(columns.index(Column::Tx), Some(0));
+    assert_eq!(columns.index(Column::Amount), Some(4));
+    assert_eq!(columns.index(Column::Destination), None);
+
+    let row = ByteRecord::from(vec!["7", "2", "", "deposit"]);
+    assert_eq!(columns.get(&row, Column::Type), Some(&b"deposit"[..]));
+    assert_eq!(columns.get(&row, Column::Amount), None);
+}
+
+#[test]
+fn test_column_map_aliases() {
+    let aliases = [
+        ("transaction_id".to_string(), Column::Tx),
+        ("Kind".to_string(), Column::Type),
+    ];
+    let headers = ByteRecord::from(vec!["kind", "client", "Transaction_ID", "amount"]);
+    let columns = ColumnMap::from_headers(&headers, &aliases).unwrap();
+    assert_eq!(columns.index(Column::Type), Some(0));
+    assert_eq!(columns.index(Column::Tx), Some(2));
+
+    let headers = ByteRecord::from(vec!["kind", "client", "id"]);
+    let err = ColumnMap::from_headers(&headers, &[]).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        EngineErrorKind::InvalidHeaders(missing) if missing == &["type", "tx", "amount"]
+    ));
+}
+
+#[test]
+fn test_names_columns() {
+    let aliases = [("kind".to_string(), Column::Type)];
+    assert!(names_columns(
+        &ByteRecord::from(vec!["type", "client", "id"]),
+        &[]
+    ));
+    assert!(names_columns(
+        &ByteRecord::from(vec![" Kind", "who"]),
+        &aliases
+    ));
+    assert!(!names_columns(
+        &ByteRecord::from(vec!["deposit", "x", "1", "2.0"]),
+        &aliases
+    ));
+}
+
+#[test]
+fn test_column_parsing() {
+    for column in Column::ALL {
+        assert_eq!(Column::new(column.name()), Some(column));
+    }
+    assert_eq!(Column::new("id"), None);
+}
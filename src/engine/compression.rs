@@ -0,0 +1,196 @@
+//! Compressed inputs. Files compressed with gzip, zstd or bzip2 are
+//! decompressed on the fly while they are read, so memory stays flat
+//! whatever their size.
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::Path;
+
+/// How an input is compressed.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    /// Tells the compression from the extension of a file, if it's one of
+    /// the usual ones.
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" | "gzip" => Some(Self::Gzip),
+            "zst" | "zstd" => Some(Self::Zstd),
+            "bz2" | "bzip2" => Some(Self::Bzip2),
+            _ => None,
+        }
+    }
+
+    /// Tells the compression from the first bytes of an input.
+    fn from_magic(head: &[u8]) -> Option<Self> {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::Gzip)
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::Zstd)
+        } else if head.starts_with(b"BZh") {
+            Some(Self::Bzip2)
+        } else {
+            None
+        }
+    }
+
+    fn decoder<'r, R: Read + 'r>(self, reader: R) -> io::Result<Box<dyn Read + 'r>> {
+        Ok(match self {
+            // Concatenated members, as `cat a.gz b.gz` makes, are read
+            // through as a single stream, the way `zcat` does.
+            Self::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(reader))),
+            Self::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Self::Bzip2 => Box::new(MultiBzDecoder::new(BufReader::new(reader))),
+        })
+    }
+}
+
+/// Opens the input file at `path`, decompressing it on the fly if it's
+/// compressed. The compression is told by the extension of the file, `.gz`,
+/// `.zst` or `.bz2`, or else by its first bytes.
+pub fn open_input<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read>> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    match Compression::from_path(path) {
+        Some(compression) => compression.decoder(file),
+        None => decompress(file),
+    }
+}
+
+/// Decompresses `reader` on the fly if it starts like a gzip, zstd or bzip2
+/// stream does, or hands it over untouched otherwise.
+pub fn decompress<'r, R: Read + 'r>(mut reader: R) -> io::Result<Box<dyn Read + 'r>> {
+    // Pipes may hand the first bytes over a few at a time.
+    let mut head = [0; 4];
+    let mut len = 0;
+    while len < head.len() {
+        match reader.read(&mut head[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+
+    // The bytes sniffed are put back in front of the rest of the stream.
+    let reader = Cursor::new(head).take(len as u64).chain(reader);
+    match Compression::from_magic(&head[..len]) {
+        Some(compression) => compression.decoder(reader),
+        None => Ok(Box::new(reader)),
+    }
+}
+
+#[cfg(test)]
+fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    match compression {
+        Compression::Gzip => {
+            let mut enc = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+            enc.write_all(data).unwrap();
+            enc.finish().unwrap()
+        }
+        Compression::Zstd => zstd::encode_all(data, 0).unwrap(),
+        Compression::Bzip2 => {
+            let mut enc = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+            enc.write_all(data).unwrap();
+            enc.finish().unwrap()
+        }
+    }
+}
+
+#[test]
+fn test_compression_detection() {
+    assert_eq!(
+        Compression::from_path(Path::new("card.csv.gz")),
+        Some(Compression::Gzip)
+    );
+    assert_eq!(
+        Compression::from_path(Path::new("card.csv.zst")),
+        Some(Compression::Zstd)
+    );
+    assert_eq!(
+        Compression::from_path(Path::new("card.csv.bz2")),
+        Some(Compression::Bzip2)
+    );
+    assert_eq!(Compression::from_path(Path::new("card.csv")), None);
+
+    for compression in [Compression::Gzip, Compression::Zstd, Compression::Bzip2] {
+        let data = compress(compression, b"type,client,tx,amount\n");
+        assert_eq!(Compression::from_magic(&data), Some(compression));
+    }
+    assert_eq!(Compression::from_magic(b"type,client"), None);
+    assert_eq!(Compression::from_magic(b""), None);
+}
+
+#[test]
+fn test_decompress() {
+    let csv = "type,client,tx,amount\ndeposit,1,1,10.0\n";
+    for compression in [Compression::Gzip, Compression::Zstd, Compression::Bzip2] {
+        let mut read = String::new();
+        decompress(compress(compression, csv.as_bytes()).as_slice())
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, csv);
+    }
+
+    // Plain text, however short, goes through untouched.
+    for plain in ["", "ty", csv] {
+        let mut read = String::new();
+        decompress(plain.as_bytes())
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, plain);
+    }
+
+    // Concatenated gzip members make a single stream.
+    let mut members = compress(Compression::Gzip, b"type,client,tx,amount\n");
+    members.extend(compress(Compression::Gzip, b"deposit,1,1,10.0\n"));
+    let mut read = String::new();
+    decompress(members.as_slice())
+        .unwrap()
+        .read_to_string(&mut read)
+        .unwrap();
+    assert_eq!(read, csv);
+}
+
+#[test]
+fn test_process_compressed_files() {
+    use super::Engine;
+
+    let dir = super::temp_path("compressed");
+    std::fs::create_dir_all(&dir).unwrap();
+    let csv = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,4.0\n";
+    let files = [
+        ("card.csv.gz", compress(Compression::Gzip, csv.as_bytes())),
+        ("card.csv.zst", compress(Compression::Zstd, csv.as_bytes())),
+        ("card.csv.bz2", compress(Compression::Bzip2, csv.as_bytes())),
+        // Told by its first bytes only.
+        ("card.archive", compress(Compression::Zstd, csv.as_bytes())),
+        ("card.csv", csv.as_bytes().to_vec()),
+    ];
+
+    for (name, content) in files {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        let mut engine = Engine::new();
+        engine.process(&path).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), "6.0".parse().unwrap());
+    }
+
+    // An extension saying otherwise than the content is an error.
+    let path = dir.join("plain.csv.gz");
+    std::fs::write(&path, csv).unwrap();
+    assert!(Engine::new().process(&path).is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,250 @@
+//! Engine configuration. Gathers the business rules that are a matter of
+//! policy rather than of correctness, so they can be chosen at runtime.
+
+use super::columns::Column;
+use super::limits::CreditLimits;
+use super::protocol::TransactionKind;
+
+/// Settings the [`Engine`](super::Engine) runs with.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// What a locked account still accepts.
+    pub frozen_policy: FrozenPolicy,
+    /// Whether withdrawals can be disputed, and how.
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    /// What happens to a row repeating a stored transaction.
+    pub duplicates: DuplicatePolicy,
+    /// How far below zero each client's available funds may go.
+    pub credit_limits: CreditLimits,
+    /// Which transactions open the account of a client seen for the first
+    /// time.
+    pub client_creation: ClientCreation,
+    /// Whether the inputs start with a header row.
+    pub header_row: HeaderRow,
+    /// Other headers the columns go by, e.g. `transaction_id` for `tx`.
+    pub header_aliases: Vec<(String, Column)>,
+}
+
+/// Whether an input starts with a header row. Without one, the columns are
+/// taken in the canonical `type,client,tx,amount,destination` order.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum HeaderRow {
+    /// A first row that parses as a transaction is one. Otherwise it's a
+    /// header row if it names at least one column, by its canonical name or
+    /// an alias, and the first data row, rejected as malformed, if it names
+    /// none. A header made of unknown names only is thus read as data
+    /// rather than refused with [`InvalidHeaders`].
+    ///
+    /// [`InvalidHeaders`]: super::EngineErrorKind::InvalidHeaders
+    #[default]
+    Detect,
+    /// The first row is always a header row.
+    Present,
+    /// There is no header row, the first row is a transaction.
+    Absent,
+}
+
+impl HeaderRow {
+    /// Returns a mode from its name, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "detect" => Some(Self::Detect),
+            "present" => Some(Self::Present),
+            "absent" => Some(Self::Absent),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to the transactions of an account frozen after a
+/// chargeback.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum FrozenPolicy {
+    /// A locked account refuses every transaction.
+    #[default]
+    RejectAll,
+    /// A locked account can still be credited, but nothing else.
+    AllowDeposits,
+    /// A locked account only goes on with the dispute process of its past
+    /// transactions: dispute, resolve and chargeback.
+    AllowDisputeResolution,
+}
+
+impl FrozenPolicy {
+    /// Returns a policy from its command line name, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "reject-all" => Some(Self::RejectAll),
+            "deposits" => Some(Self::AllowDeposits),
+            "disputes" => Some(Self::AllowDisputeResolution),
+            _ => None,
+        }
+    }
+
+    /// Checks if a transaction of this kind may hit a locked account.
+    pub fn allows(self, kind: TransactionKind) -> bool {
+        match self {
+            Self::RejectAll => false,
+            Self::AllowDeposits => kind == TransactionKind::Deposit,
+            Self::AllowDisputeResolution => matches!(
+                kind,
+                TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Chargeback
+            ),
+        }
+    }
+}
+
+/// How a dispute over a withdrawal is handled. Disputing a withdrawal means
+/// the client claims the funds back, so it credits the account where a
+/// disputed deposit debits it.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum WithdrawalDisputePolicy {
+    /// Withdrawals can't be disputed.
+    #[default]
+    Refuse,
+    /// A dispute holds the withdrawn amount on top of the balance (held and
+    /// total increase). A resolve drops it, the withdrawal standing as it
+    /// was, and a chargeback releases it to the available funds, giving
+    /// the money back to the client and freezing the account.
+    Credit,
+}
+
+impl WithdrawalDisputePolicy {
+    /// Returns a policy from its command line name, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "refuse" => Some(Self::Refuse),
+            "credit" => Some(Self::Credit),
+            _ => None,
+        }
+    }
+}
+
+/// Which transactions open an account for a client not known yet. The
+/// account is opened along with the first transaction applied to it, a
+/// rejected one leaving no account behind. Any other transaction for an
+/// unknown client is rejected with `ClientNotFound`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ClientCreation {
+    /// Accounts are never opened, clients have to come from the state
+    /// snapshot.
+    Never,
+    /// Only a deposit opens an account.
+    #[default]
+    Deposits,
+    /// Any transaction opens an account, e.g. a withdrawal within the
+    /// client's credit limit.
+    Always,
+}
+
+impl ClientCreation {
+    /// Returns a policy from its command line name, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "never" => Some(Self::Never),
+            "deposits" => Some(Self::Deposits),
+            "always" => Some(Self::Always),
+            _ => None,
+        }
+    }
+
+    /// Checks if a transaction of this kind opens an unknown client's
+    /// account.
+    pub fn creates(self, kind: TransactionKind) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Deposits => kind == TransactionKind::Deposit,
+            Self::Always => true,
+        }
+    }
+}
+
+/// What happens to a deposit or a withdrawal whose id is already taken.
+/// Either way, it's caught before any balance moves.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DuplicatePolicy {
+    /// Rejected with `TransactionAlreadyExists`, even when it repeats the
+    /// stored transaction exactly.
+    #[default]
+    Reject,
+    /// A row repeating the stored transaction exactly, same kind, client
+    /// and amount, is taken as already applied and skipped without error,
+    /// so replaying an input is harmless. Any other reuse of the id is
+    /// still rejected.
+    Ignore,
+}
+
+impl DuplicatePolicy {
+    /// Returns a policy from its command line name, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "reject" => Some(Self::Reject),
+            "ignore" => Some(Self::Ignore),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_frozen_policy_parsing() {
+    assert_eq!(
+        FrozenPolicy::new("reject-all"),
+        Some(FrozenPolicy::RejectAll)
+    );
+    assert_eq!(
+        FrozenPolicy::new("deposits"),
+        Some(FrozenPolicy::AllowDeposits)
+    );
+    assert_eq!(
+        FrozenPolicy::new("disputes"),
+        Some(FrozenPolicy::AllowDisputeResolution)
+    );
+    assert_eq!(FrozenPolicy::new("whatever"), None);
+}
+
+#[test]
+fn test_withdrawal_dispute_policy_parsing() {
+    assert_eq!(
+        WithdrawalDisputePolicy::new("refuse"),
+        Some(WithdrawalDisputePolicy::Refuse)
+    );
+    assert_eq!(
+        WithdrawalDisputePolicy::new("credit"),
+        Some(WithdrawalDisputePolicy::Credit)
+    );
+    assert_eq!(WithdrawalDisputePolicy::new("debit"), None);
+}
+
+#[test]
+fn test_header_row_parsing() {
+    assert_eq!(HeaderRow::new("detect"), Some(HeaderRow::Detect));
+    assert_eq!(HeaderRow::new("present"), Some(HeaderRow::Present));
+    assert_eq!(HeaderRow::new("absent"), Some(HeaderRow::Absent));
+    assert_eq!(HeaderRow::new("maybe"), None);
+}
+
+#[test]
+fn test_duplicate_policy_parsing() {
+    assert_eq!(
+        DuplicatePolicy::new("reject"),
+        Some(DuplicatePolicy::Reject)
+    );
+    assert_eq!(
+        DuplicatePolicy::new("ignore"),
+        Some(DuplicatePolicy::Ignore)
+    );
+    assert_eq!(DuplicatePolicy::new("replace"), None);
+}
+
+#[test]
+fn test_client_creation_parsing() {
+    assert_eq!(ClientCreation::new("never"), Some(ClientCreation::Never));
+    assert_eq!(
+        ClientCreation::new("deposits"),
+        Some(ClientCreation::Deposits)
+    );
+    assert_eq!(ClientCreation::new("always"), Some(ClientCreation::Always));
+    assert_eq!(ClientCreation::new("sometimes"), None);
+    assert!(ClientCreation::Deposits.creates(TransactionKind::Deposit));
+    assert!(!ClientCreation::Deposits.creates(TransactionKind::Withdrawal));
+}
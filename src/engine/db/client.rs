@@ -1,40 +1,57 @@
 //! Client logic implementation.
 
 use super::error::{DBError, Result};
+use crate::engine::amount::Amount;
 use std::collections::BTreeMap;
 
 pub type ClientDB = BTreeMap<u16, ClientAccountState>;
 
 /// Data structure storing all the info needed about a Client.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ClientAccountState {
-    available: f32,
+    available: Amount,
     /// A held value correspond to the amount of
     /// a disputed transaction.
-    held: f32,
-    total: f32,
+    held: Amount,
+    total: Amount,
     locked: bool,
 }
 
+impl Default for ClientAccountState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ClientAccountState {
     pub fn new() -> Self {
         ClientAccountState {
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
             locked: false,
         }
     }
 
-    pub fn available(&self) -> f32 {
+    /// Rebuilds an account from its balances, as saved in a snapshot.
+    pub(crate) fn from_parts(available: Amount, held: Amount, total: Amount, locked: bool) -> Self {
+        ClientAccountState {
+            available,
+            held,
+            total,
+            locked,
+        }
+    }
+
+    pub fn available(&self) -> Amount {
         self.available
     }
 
-    pub fn held(&self) -> f32 {
+    pub fn held(&self) -> Amount {
         self.held
     }
 
-    pub fn total(&self) -> f32 {
+    pub fn total(&self) -> Amount {
         self.total
     }
 
@@ -43,28 +60,30 @@ impl ClientAccountState {
     }
 
     /// Add some value to the client's account.
-    pub fn add(&mut self, x: f32) -> Result<()> {
-        if x >= 0.0 {
-            self.available += x;
-            self.total += x;
-            Ok(())
-        } else {
-            Err(DBError::NegativeAmountEncountered)
+    pub fn add(&mut self, x: Amount) -> Result<()> {
+        if x.is_negative() {
+            return Err(DBError::NegativeAmountEncountered);
         }
+        let available = checked(self.available.checked_add(x))?;
+        let total = checked(self.total.checked_add(x))?;
+        self.available = available;
+        self.total = total;
+        Ok(())
     }
 
     /// Subtracting value from the client's account if it's possible,
     /// returns an error otherwise.
     /// We choose to denie it if there is not enough credit available
     /// in a client's account.
-    pub fn sub(&mut self, x: f32) -> Result<()> {
-        if x >= 0.0 {
-            self.available -= x;
-            self.total -= x;
-            Ok(())
-        } else {
-            Err(DBError::NegativeAmountEncountered)
+    pub fn sub(&mut self, x: Amount) -> Result<()> {
+        if x.is_negative() {
+            return Err(DBError::NegativeAmountEncountered);
         }
+        let available = checked(self.available.checked_sub(x))?;
+        let total = checked(self.total.checked_sub(x))?;
+        self.available = available;
+        self.total = total;
+        Ok(())
     }
 
     /// Locks the client's account during the time of dispute.
@@ -72,25 +91,50 @@ impl ClientAccountState {
         self.locked = true;
     }
 
-    #[allow(dead_code)]
     /// Locks the client's account during the time of dispute.
     pub fn unlock(&mut self) {
         self.locked = false;
     }
 
     /// Hold value from the client during dispute.
-    pub fn hold(&mut self, x: f32) {
-        self.available -= x;
-        self.held += x;
+    pub fn hold(&mut self, x: Amount) -> Result<()> {
+        let available = checked(self.available.checked_sub(x))?;
+        let held = checked(self.held.checked_add(x))?;
+        self.available = available;
+        self.held = held;
+        Ok(())
     }
 
     // Releases the held funds and add it back to the available
     // amount.
-    pub fn unhold(&mut self, x: f32) -> Result<()> {
+    pub fn unhold(&mut self, x: Amount) -> Result<()> {
         // Check if there is a enough held values.
         if self.held >= x {
-            self.held -= x;
-            self.available += x;
+            let available = checked(self.available.checked_add(x))?;
+            self.held = checked(self.held.checked_sub(x))?;
+            self.available = available;
+            Ok(())
+        } else {
+            Err(DBError::NotEnoughHeldValue)
+        }
+    }
+
+    /// Holds the amount of a disputed withdrawal on top of the balance,
+    /// pending the dispute outcome.
+    pub fn credit_held(&mut self, x: Amount) -> Result<()> {
+        let held = checked(self.held.checked_add(x))?;
+        let total = checked(self.total.checked_add(x))?;
+        self.held = held;
+        self.total = total;
+        Ok(())
+    }
+
+    /// Removes held funds from the account altogether.
+    pub fn debit_held(&mut self, x: Amount) -> Result<()> {
+        if self.held >= x {
+            let total = checked(self.total.checked_sub(x))?;
+            self.held = checked(self.held.checked_sub(x))?;
+            self.total = total;
             Ok(())
         } else {
             Err(DBError::NotEnoughHeldValue)
@@ -98,30 +142,64 @@ impl ClientAccountState {
     }
 }
 
+/// Maps the result of a checked [`Amount`] operation to our error type.
+fn checked(x: Option<Amount>) -> Result<Amount> {
+    x.ok_or(DBError::AmountOverflow)
+}
+
 #[test]
 fn test_sub() {
     let mut cas = ClientAccountState::new();
-    cas.add(3.0).unwrap();
-    cas.sub(1.0).unwrap();
-    assert_eq!(cas.available(), 2.0);
-    assert_eq!(cas.total(), 2.0);
+    cas.add("3.0".parse().unwrap()).unwrap();
+    cas.sub("1.0".parse().unwrap()).unwrap();
+    assert_eq!(cas.available(), "2.0".parse().unwrap());
+    assert_eq!(cas.total(), "2.0".parse().unwrap());
 }
 
 #[test]
 fn test_add() {
     let mut cas = ClientAccountState::new();
-    cas.add(10.0).unwrap();
-    assert_eq!(cas.available(), 10.0);
-    assert_eq!(cas.total(), 10.0);
-    assert!(cas.add(-1.0).is_err());
+    cas.add("10.0".parse().unwrap()).unwrap();
+    assert_eq!(cas.available(), "10.0".parse().unwrap());
+    assert_eq!(cas.total(), "10.0".parse().unwrap());
+    assert!(cas.add("-1.0".parse().unwrap()).is_err());
+}
+
+#[test]
+fn test_add_overflow() {
+    let mut cas = ClientAccountState::new();
+    cas.add(Amount::from_raw(i64::MAX)).unwrap();
+    assert_eq!(cas.add(Amount::from_raw(1)), Err(DBError::AmountOverflow));
+    // A failing operation leaves the account untouched.
+    assert_eq!(cas.available(), Amount::from_raw(i64::MAX));
+    assert_eq!(cas.total(), Amount::from_raw(i64::MAX));
 }
 
 #[test]
 fn test_hold() {
     let mut cas = ClientAccountState::new();
-    cas.add(3.0).unwrap();
-    cas.hold(1.0);
+    cas.add("3.0".parse().unwrap()).unwrap();
+    cas.hold("1.0".parse().unwrap()).unwrap();
 
-    assert_eq!(cas.total(), 3.0);
-    assert_eq!(cas.held(), 1.0);
+    assert_eq!(cas.total(), "3.0".parse().unwrap());
+    assert_eq!(cas.held(), "1.0".parse().unwrap());
+}
+
+#[test]
+fn test_credit_held() {
+    let mut cas = ClientAccountState::new();
+    cas.add("3.0".parse().unwrap()).unwrap();
+    cas.credit_held("1.0".parse().unwrap()).unwrap();
+
+    assert_eq!(cas.available(), "3.0".parse().unwrap());
+    assert_eq!(cas.held(), "1.0".parse().unwrap());
+    assert_eq!(cas.total(), "4.0".parse().unwrap());
+
+    cas.debit_held("1.0".parse().unwrap()).unwrap();
+    assert_eq!(cas.held(), Amount::ZERO);
+    assert_eq!(cas.total(), "3.0".parse().unwrap());
+    assert_eq!(
+        cas.debit_held("1.0".parse().unwrap()),
+        Err(DBError::NotEnoughHeldValue)
+    );
 }
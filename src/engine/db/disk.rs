@@ -0,0 +1,402 @@
+//! Disk backed storage, for inputs holding more transactions than we can
+//! afford to keep in memory.
+
+use super::client::{ClientAccountState, ClientDB};
+use super::error::{DBError, Result};
+use super::{Storage, TransactionDB};
+use crate::engine::amount::Amount;
+use crate::engine::protocol::{Transaction, TransactionKind, TransactionState};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+/// Size of a transaction slot in the index file.
+pub(crate) const SLOT_SIZE: usize = 16;
+/// Rough memory footprint of a transaction waiting in the write-back cache,
+/// map node overhead included.
+const CACHED_TX_SIZE: usize = 64;
+/// How many slots are read at once when scanning the whole index.
+const SCAN_SLOTS: usize = 4096;
+/// Memory budget of the write-back cache when none is given.
+pub const DEFAULT_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+
+/// [`Storage`] keeping the client accounts in memory, they are bounded by the
+/// `u16` id space, and the transactions in an index file on disk.
+///
+/// The index is made of fixed size slots, the one of transaction `tx` being
+/// at offset `tx * 16`, so any transaction is a single seek away. The file is
+/// sparse: the ids that are never used don't take any room on disk.
+///
+/// New and updated transactions first go to a write-back cache. Once it
+/// outgrows the memory budget, the cache is spilled to the index file in id
+/// order, so sequential ids end up written in a single go.
+///
+/// A slot is laid out as follow, integers being little endian:
+///
+/// | bytes  | content                                                        |
+/// |--------|----------------------------------------------------------------|
+/// | 0      | kind: 0 for an empty slot, 1 deposit, 2 withdrawal, 3 transfer |
+/// | 1      | dispute state                                                  |
+/// | 2..4   | client id                                                      |
+/// | 4..12  | amount, in ten-thousandths of a unit                           |
+/// | 12..14 | destination client id of a transfer                            |
+/// | 14..16 | reserved                                                       |
+pub struct DiskStorage {
+    client_db: ClientDB,
+    /// Transactions not written to the index file yet.
+    cache: TransactionDB,
+    /// How many transactions the cache holds before being spilled.
+    cache_capacity: usize,
+    index: File,
+    /// Length of the index file. Slots beyond it are known to be empty
+    /// without reaching the disk.
+    index_len: u64,
+}
+
+impl DiskStorage {
+    /// Creates a storage whose transaction index lives at `path`, truncating
+    /// the file if it exists. The write-back cache holds on to roughly
+    /// `memory_budget` bytes at most.
+    pub fn create<P: AsRef<Path>>(path: P, memory_budget: usize) -> io::Result<Self> {
+        let index = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            client_db: ClientDB::new(),
+            cache: TransactionDB::new(),
+            cache_capacity: (memory_budget / CACHED_TX_SIZE).max(1),
+            index,
+            index_len: 0,
+        })
+    }
+
+    /// Writes the cached transactions to the index file and empties the
+    /// cache. Runs of consecutive ids are written at once. If a write fails,
+    /// the cache is kept whole for the next spill to try again.
+    pub fn spill(&mut self) -> Result<()> {
+        let cache = mem::take(&mut self.cache);
+        let written = self.write_cache(&cache);
+        if written.is_err() {
+            self.cache = cache;
+        }
+        written
+    }
+
+    /// Writes `cache` to the index file.
+    fn write_cache(&mut self, cache: &TransactionDB) -> Result<()> {
+        let mut run: Vec<u8> = Vec::new();
+        let mut run_start = 0;
+
+        for (tx, trx) in cache.iter() {
+            let next = run_start + (run.len() / SLOT_SIZE) as u64;
+            if !run.is_empty() && u64::from(*tx) != next {
+                self.write_slots(run_start, &run)?;
+                run.clear();
+            }
+            if run.is_empty() {
+                run_start = u64::from(*tx);
+            }
+            run.extend_from_slice(&encode_transaction(trx));
+        }
+        if !run.is_empty() {
+            self.write_slots(run_start, &run)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `slots` to the index file, starting at slot `first`.
+    fn write_slots(&mut self, first: u64, slots: &[u8]) -> Result<()> {
+        let offset = first * SLOT_SIZE as u64;
+        self.index.seek(SeekFrom::Start(offset))?;
+        self.index.write_all(slots)?;
+        self.index_len = self.index_len.max(offset + slots.len() as u64);
+        Ok(())
+    }
+
+    /// Reads the slot of `tx` from the index file.
+    fn read_slot(&self, tx: u32) -> Result<Option<Transaction>> {
+        let offset = u64::from(tx) * SLOT_SIZE as u64;
+        if offset >= self.index_len {
+            return Ok(None);
+        }
+
+        let mut slot = [0u8; SLOT_SIZE];
+        let mut index = &self.index;
+        index.seek(SeekFrom::Start(offset))?;
+        index.read_exact(&mut slot)?;
+        decode_transaction(&slot)
+    }
+
+    /// Puts `trx` in the write-back cache, spilling it first if it's full,
+    /// so `trx` is stored nowhere if the spill fails.
+    fn cache(&mut self, tx: u32, trx: Transaction) -> Result<()> {
+        if self.cache.len() >= self.cache_capacity {
+            self.spill()?;
+        }
+        self.cache.insert(tx, trx);
+        Ok(())
+    }
+}
+
+impl Storage for DiskStorage {
+    fn client(&self, client: u16) -> Option<ClientAccountState> {
+        self.client_db.get(&client).copied()
+    }
+
+    fn set_client(&mut self, client: u16, cas: ClientAccountState) {
+        self.client_db.insert(client, cas);
+    }
+
+    fn clients(&self) -> Box<dyn Iterator<Item = (u16, ClientAccountState)> + '_> {
+        Box::new(self.client_db.iter().map(|(client, cas)| (*client, *cas)))
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<(u32, Transaction)>> + '_> {
+        let scan = IndexScan {
+            storage: self,
+            chunk: Vec::new(),
+            chunk_start: 0,
+            pos: 0,
+        };
+        let cached = self.cache.iter().map(|(tx, trx)| Ok((*tx, *trx)));
+        Box::new(scan.chain(cached))
+    }
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>> {
+        match self.cache.get(&tx) {
+            Some(trx) => Ok(Some(*trx)),
+            None => self.read_slot(tx),
+        }
+    }
+
+    fn insert_transaction(&mut self, tx: u32, trx: Transaction) -> Result<()> {
+        if self.transaction(tx)?.is_some() {
+            return Err(DBError::TransactionAlreadyExists);
+        }
+        self.cache(tx, trx)
+    }
+
+    fn update_transaction(&mut self, tx: u32, trx: Transaction) -> Result<()> {
+        if self.transaction(tx)?.is_none() {
+            return Err(DBError::TransactionNotFound);
+        }
+        self.cache(tx, trx)
+    }
+}
+
+/// Walks the index file, skipping the empty slots and the ones shadowed by
+/// the write-back cache.
+struct IndexScan<'a> {
+    storage: &'a DiskStorage,
+    chunk: Vec<u8>,
+    /// Offset of the chunk in the index file.
+    chunk_start: u64,
+    /// Offset of the next slot in the chunk.
+    pos: usize,
+}
+
+impl Iterator for IndexScan<'_> {
+    type Item = Result<(u32, Transaction)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.pos >= self.chunk.len() {
+                let start = self.chunk_start + self.chunk.len() as u64;
+                let left = self.storage.index_len.saturating_sub(start);
+                if left == 0 {
+                    return None;
+                }
+                self.chunk
+                    .resize(left.min((SCAN_SLOTS * SLOT_SIZE) as u64) as usize, 0);
+                self.chunk_start = start;
+                self.pos = 0;
+
+                let mut index = &self.storage.index;
+                let read = index
+                    .seek(SeekFrom::Start(start))
+                    .and_then(|_| index.read_exact(&mut self.chunk));
+                if let Err(err) = read {
+                    // Stops the scan for good.
+                    self.chunk.clear();
+                    self.chunk_start = self.storage.index_len;
+                    return Some(Err(err.into()));
+                }
+            }
+
+            let tx = ((self.chunk_start + self.pos as u64) / SLOT_SIZE as u64) as u32;
+            let mut slot = [0u8; SLOT_SIZE];
+            slot.copy_from_slice(&self.chunk[self.pos..self.pos + SLOT_SIZE]);
+            self.pos += SLOT_SIZE;
+
+            if self.storage.cache.contains_key(&tx) {
+                continue;
+            }
+            match decode_transaction(&slot) {
+                Ok(Some(trx)) => return Some(Ok((tx, trx))),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+pub(crate) fn encode_transaction(trx: &Transaction) -> [u8; SLOT_SIZE] {
+    let mut slot = [0u8; SLOT_SIZE];
+    slot[0] = match trx.kind() {
+        TransactionKind::Deposit => 1,
+        TransactionKind::Withdrawal => 2,
+        TransactionKind::Transfer => 3,
+        // Only deposits, withdrawals and transfers are stored.
+        _ => 0,
+    };
+    slot[1] = match trx.state() {
+        TransactionState::Processed => 0,
+        TransactionState::Disputed => 1,
+        TransactionState::Resolved => 2,
+        TransactionState::ChargedBack => 3,
+    };
+    slot[2..4].copy_from_slice(&trx.client_id().to_le_bytes());
+    slot[4..12].copy_from_slice(&trx.amount().raw().to_le_bytes());
+    if let Some(destination) = trx.destination() {
+        slot[12..14].copy_from_slice(&destination.to_le_bytes());
+    }
+    slot
+}
+
+/// Reads back a slot, None meaning it's empty. A slot we can't make sense
+/// of means the index file has been tampered with.
+pub(crate) fn decode_transaction(slot: &[u8; SLOT_SIZE]) -> Result<Option<Transaction>> {
+    let kind = match slot[0] {
+        0 => return Ok(None),
+        1 => TransactionKind::Deposit,
+        2 => TransactionKind::Withdrawal,
+        3 => TransactionKind::Transfer,
+        _ => return Err(DBError::StorageFailure(io::ErrorKind::InvalidData)),
+    };
+    let state = match slot[1] {
+        0 => TransactionState::Processed,
+        1 => TransactionState::Disputed,
+        2 => TransactionState::Resolved,
+        3 => TransactionState::ChargedBack,
+        _ => return Err(DBError::StorageFailure(io::ErrorKind::InvalidData)),
+    };
+    let client = u16::from_le_bytes([slot[2], slot[3]]);
+    let mut amount = [0u8; 8];
+    amount.copy_from_slice(&slot[4..12]);
+
+    let amount = Amount::from_raw(i64::from_le_bytes(amount));
+
+    let mut trx = match kind {
+        TransactionKind::Transfer => {
+            Transaction::transfer(client, u16::from_le_bytes([slot[12], slot[13]]), amount)
+        }
+        _ => Transaction::new(kind, client, amount),
+    };
+    trx.set_state(state);
+    Ok(Some(trx))
+}
+
+#[test]
+fn test_disk_storage_spill() {
+    let path = crate::engine::temp_path("spill.idx");
+    // Room for two cached transactions only.
+    let mut db = DiskStorage::create(&path, 2 * CACHED_TX_SIZE).unwrap();
+    let amount: Amount = "1.5".parse().unwrap();
+
+    for tx in [1, 2, 3, 10, 1_000_000] {
+        let trx = Transaction::new(TransactionKind::Deposit, 7, amount);
+        db.insert_transaction(tx, trx).unwrap();
+    }
+    assert!(db.cache.len() < 2);
+    assert_eq!(
+        db.insert_transaction(2, Transaction::new(TransactionKind::Withdrawal, 7, amount)),
+        Err(DBError::TransactionAlreadyExists)
+    );
+
+    let mut trx = db.transaction(10).unwrap().unwrap();
+    trx.set_state(TransactionState::Disputed);
+    db.update_transaction(10, trx).unwrap();
+    db.spill().unwrap();
+
+    let trx = db.transaction(10).unwrap().unwrap();
+    assert_eq!(trx.kind(), TransactionKind::Deposit);
+    assert_eq!(trx.client_id(), 7);
+    assert_eq!(trx.amount(), amount);
+    assert_eq!(trx.state(), TransactionState::Disputed);
+    assert!(db.transaction(4).unwrap().is_none());
+    assert!(db.transaction(u32::MAX).unwrap().is_none());
+    assert_eq!(
+        db.update_transaction(4, trx),
+        Err(DBError::TransactionNotFound)
+    );
+
+    // Half in the index file, half in the cache.
+    db.insert_transaction(5, trx).unwrap();
+    db.update_transaction(3, trx).unwrap();
+    let mut ids: Vec<u32> = db.transactions().map(|stored| stored.unwrap().0).collect();
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 2, 3, 5, 10, 1_000_000]);
+
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_disk_storage_spill_failure() {
+    let path = crate::engine::temp_path("spill-failure.idx");
+    let mut db = DiskStorage::create(&path, 2 * CACHED_TX_SIZE).unwrap();
+    let trx = Transaction::new(TransactionKind::Deposit, 7, "1.5".parse().unwrap());
+    db.insert_transaction(1, trx).unwrap();
+    db.insert_transaction(2, trx).unwrap();
+
+    // The index file can't be written to anymore.
+    let writable = mem::replace(&mut db.index, File::open(&path).unwrap());
+    assert!(matches!(
+        db.insert_transaction(3, trx),
+        Err(DBError::StorageFailure(_))
+    ));
+    assert_eq!(db.cache.len(), 2);
+    assert!(db.transaction(1).unwrap().is_some());
+    assert!(db.transaction(3).unwrap().is_none());
+
+    db.index = writable;
+    db.insert_transaction(3, trx).unwrap();
+    for tx in 1..=3 {
+        assert_eq!(db.transaction(tx).unwrap(), Some(trx));
+    }
+
+    drop(db);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_disk_storage_slot_roundtrip() {
+    let amount: Amount = "-42.1234".parse().unwrap();
+    let mut trx = Transaction::new(TransactionKind::Withdrawal, u16::MAX, amount);
+    trx.set_state(TransactionState::ChargedBack);
+
+    let slot = encode_transaction(&trx);
+    let back = decode_transaction(&slot).unwrap().unwrap();
+    assert_eq!(back.kind(), TransactionKind::Withdrawal);
+    assert_eq!(back.client_id(), u16::MAX);
+    assert_eq!(back.amount(), amount);
+    assert_eq!(back.state(), TransactionState::ChargedBack);
+    assert_eq!(back.destination(), None);
+
+    let transfer = Transaction::transfer(3, u16::MAX, "1.5".parse().unwrap());
+    assert_eq!(
+        decode_transaction(&encode_transaction(&transfer)).unwrap(),
+        Some(transfer)
+    );
+
+    assert!(decode_transaction(&[0; SLOT_SIZE]).unwrap().is_none());
+    assert_eq!(
+        decode_transaction(&[9; SLOT_SIZE]).err(),
+        Some(DBError::StorageFailure(io::ErrorKind::InvalidData))
+    );
+}
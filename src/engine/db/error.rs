@@ -1,17 +1,46 @@
 //! Database Error implementation.
+use std::io;
+
 pub type Result<T> = std::result::Result<T, DBError>;
 
+/// Why a record couldn't be applied to the databases.
 #[non_exhaustive]
 #[derive(Debug, PartialEq)]
 pub enum DBError {
-    #[allow(dead_code)]
+    /// The operation doesn't apply to this transaction.
     OperationNotPermitted,
     NegativeAmountEncountered,
+    /// A transaction with the same id is already stored.
     TransactionAlreadyExists,
+    /// The transaction referred to is unknown.
     TransactionNotFound,
+    /// Resolve and chargeback only apply to a disputed transaction.
     TransactionNotInDispute,
+    /// The transaction is already under dispute.
+    TransactionAlreadyDisputed,
+    /// The dispute over this transaction has already been resolved.
+    TransactionAlreadyResolved,
+    /// The dispute over this transaction already ended with a chargeback.
+    TransactionAlreadyChargedBack,
     NotEnoughAvailableCredit,
     NotEnoughHeldValue,
     ClientNotFound,
+    /// The transaction referred to belongs to another client.
     ClientIdMismatch,
+    /// The account is frozen and the frozen account policy refuses
+    /// this kind of transaction.
+    AccountLocked,
+    /// A balance would go beyond what an [`Amount`] can represent.
+    ///
+    /// [`Amount`]: crate::engine::amount::Amount
+    AmountOverflow,
+    /// The storage backend failed to reach its data. Unlike the others,
+    /// this one isn't about the record and the run can't go on.
+    StorageFailure(io::ErrorKind),
+}
+
+impl From<io::Error> for DBError {
+    fn from(err: io::Error) -> Self {
+        DBError::StorageFailure(err.kind())
+    }
 }
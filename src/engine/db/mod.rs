@@ -1,15 +1,61 @@
 //! Database handling part where are stored the Client account's states and the important
 //! transactions that we need to keep track of.
 pub mod client;
+mod disk;
 mod error;
 use crate::engine::protocol::Transaction;
-use client::ClientDB;
+use client::{ClientAccountState, ClientDB};
+pub(crate) use disk::{decode_transaction, encode_transaction, SLOT_SIZE};
+pub use disk::{DiskStorage, DEFAULT_MEMORY_BUDGET};
 pub use error::{DBError, Result};
+use std::collections::btree_map::Entry;
 use std::collections::BTreeMap;
 
 pub type TransactionDB = BTreeMap<u32, Transaction>;
 
+/// Storage backend of the client accounts and of the transactions we keep
+/// track of. The engine only talks to this trait, so the in-memory [`DB`]
+/// can be swapped for another store.
+///
+/// Accounts are bounded by the `u16` client id space and are expected to be
+/// cheap to reach, hence their operations can't fail. Transactions are the
+/// ones growing with the input, and a store may fail to reach them.
+pub trait Storage: Send {
+    /// Returns the state of a client's account, or None if unknown.
+    fn client(&self, client: u16) -> Option<ClientAccountState>;
+
+    /// Inserts or replaces the state of a client's account.
+    fn set_client(&mut self, client: u16, cas: ClientAccountState);
+
+    /// Iterates over every client's account, ordered by client id.
+    fn clients(&self) -> Box<dyn Iterator<Item = (u16, ClientAccountState)> + '_>;
+
+    /// Iterates over every stored transaction, in no particular order.
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<(u32, Transaction)>> + '_>;
+
+    /// Returns a stored transaction, or None if unknown.
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>>;
+
+    /// Stores a new transaction. Fails with
+    /// [`DBError::TransactionAlreadyExists`] if its id is already taken.
+    fn insert_transaction(&mut self, tx: u32, trx: Transaction) -> Result<()>;
+
+    /// Replaces a stored transaction. Fails with
+    /// [`DBError::TransactionNotFound`] if it's unknown.
+    fn update_transaction(&mut self, tx: u32, trx: Transaction) -> Result<()>;
+
+    /// Stores a transaction, replacing it if it's already there.
+    fn put_transaction(&mut self, tx: u32, trx: Transaction) -> Result<()> {
+        match self.transaction(tx)? {
+            Some(_) => self.update_transaction(tx, trx),
+            None => self.insert_transaction(tx, trx),
+        }
+    }
+}
+
 /// Data struct used to store our databases. Uses BTreeMaps under the hood for speed.
+/// This is the default [`Storage`] backend.
+#[derive(Default)]
 pub struct DB {
     client_db: ClientDB,
     transaction_db: TransactionDB,
@@ -22,21 +68,83 @@ impl DB {
             transaction_db: TransactionDB::new(),
         }
     }
+}
 
-    pub fn get_client_db(&self) -> &ClientDB {
-        &self.client_db
+impl Storage for DB {
+    fn client(&self, client: u16) -> Option<ClientAccountState> {
+        self.client_db.get(&client).copied()
     }
 
-    pub fn get_mut_client_db(&mut self) -> &mut ClientDB {
-        &mut self.client_db
+    fn set_client(&mut self, client: u16, cas: ClientAccountState) {
+        self.client_db.insert(client, cas);
     }
 
-    #[allow(dead_code)]
-    pub fn get_transaction_db(&self) -> &TransactionDB {
-        &self.transaction_db
+    fn clients(&self) -> Box<dyn Iterator<Item = (u16, ClientAccountState)> + '_> {
+        Box::new(self.client_db.iter().map(|(client, cas)| (*client, *cas)))
     }
 
-    pub fn get_mut_transaction_db(&mut self) -> &mut TransactionDB {
-        &mut self.transaction_db
+    fn transactions(&self) -> Box<dyn Iterator<Item = Result<(u32, Transaction)>> + '_> {
+        Box::new(self.transaction_db.iter().map(|(tx, trx)| Ok((*tx, *trx))))
     }
+
+    fn transaction(&self, tx: u32) -> Result<Option<Transaction>> {
+        Ok(self.transaction_db.get(&tx).copied())
+    }
+
+    fn insert_transaction(&mut self, tx: u32, trx: Transaction) -> Result<()> {
+        match self.transaction_db.entry(tx) {
+            Entry::Occupied(_) => Err(DBError::TransactionAlreadyExists),
+            Entry::Vacant(entry) => {
+                entry.insert(trx);
+                Ok(())
+            }
+        }
+    }
+
+    fn update_transaction(&mut self, tx: u32, trx: Transaction) -> Result<()> {
+        match self.transaction_db.get_mut(&tx) {
+            Some(stored) => {
+                *stored = trx;
+                Ok(())
+            }
+            None => Err(DBError::TransactionNotFound),
+        }
+    }
+}
+
+#[test]
+fn test_db_insert_transaction_twice() {
+    use crate::engine::protocol::TransactionKind;
+    let mut db = DB::new();
+    let trx = Transaction::new(TransactionKind::Deposit, 1, "1.0".parse().unwrap());
+
+    assert_eq!(db.insert_transaction(1, trx), Ok(()));
+    assert_eq!(
+        db.insert_transaction(1, trx),
+        Err(DBError::TransactionAlreadyExists)
+    );
+    assert_eq!(db.transaction(1).unwrap().unwrap().client_id(), 1);
+}
+
+#[test]
+fn test_db_update_unknown_transaction() {
+    use crate::engine::protocol::TransactionKind;
+    let mut db = DB::new();
+    let trx = Transaction::new(TransactionKind::Deposit, 1, "1.0".parse().unwrap());
+
+    assert_eq!(
+        db.update_transaction(1, trx),
+        Err(DBError::TransactionNotFound)
+    );
+    assert!(db.transaction(1).unwrap().is_none());
+}
+
+#[test]
+fn test_db_clients_ordered() {
+    let mut db = DB::new();
+    db.set_client(2, ClientAccountState::new());
+    db.set_client(1, ClientAccountState::new());
+
+    let ids: Vec<u16> = db.clients().map(|(client, _)| client).collect();
+    assert_eq!(ids, vec![1, 2]);
 }
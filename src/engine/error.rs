@@ -5,6 +5,7 @@ use super::record::RecordError;
 use crate::engine::db::DBError;
 use csv::Error as CsvError;
 use std::fmt;
+use std::io;
 
 /// A type alias for `Result<T, engine::Error>`.
 pub type Result<T> = std::result::Result<T, EngineError>;
@@ -17,21 +18,37 @@ impl EngineError {
         EngineError(Box::new(kind))
     }
 
-    // pub fn kind(&self) -> &EngineErrorKind {
-    //     &self.0
-    // }
+    pub fn kind(&self) -> &EngineErrorKind {
+        &self.0
+    }
+
+    pub fn into_kind(self) -> EngineErrorKind {
+        *self.0
+    }
 
-    // pub fn into_kind(self) -> EngineErrorKind {
-    //     *self.0
-    // }
+    /// Whether the run can't go on after this error, as the databases
+    /// can't be trusted anymore.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            *self.0,
+            EngineErrorKind::DBError(DBError::StorageFailure(_))
+        )
+    }
 }
 #[derive(Debug)]
 pub enum EngineErrorKind {
     DBError(DBError),
     RecordError(RecordError),
     CsvError(CsvError),
-    InvalidHeaders,
-    #[allow(dead_code)]
+    IoError(io::Error),
+    /// The header row lacks these columns.
+    InvalidHeaders(Vec<String>),
+    /// The state snapshot is damaged or written by an unsupported version.
+    InvalidSnapshot,
+    /// The journal is damaged or written by an unsupported version.
+    InvalidJournal,
+    /// The client configuration holds an invalid credit limit at this line.
+    InvalidCreditLimit(u64),
     NotEnoughAvailableCredit,
     UnknownTransaction,
 }
@@ -54,12 +71,20 @@ impl From<CsvError> for EngineError {
     }
 }
 
+impl From<io::Error> for EngineError {
+    fn from(err: io::Error) -> EngineError {
+        EngineError::new(EngineErrorKind::IoError(err))
+    }
+}
+
 // impl From<> for EngineError {
 //     fn from(err: CsvError) -> EngineError {
 //         EngineError::new(EngineErrorKind::CsvError(err))
 //     }
 // }
 
+impl std::error::Error for EngineError {}
+
 impl fmt::Display for EngineError {
     /// We would need more info about the errors occuring here in a real implementation.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -67,7 +92,17 @@ impl fmt::Display for EngineError {
             EngineErrorKind::DBError(ref err) => write!(f, "Database error: {:?}", err),
             EngineErrorKind::RecordError(ref _err) => write!(f, "Record parsing error"),
             EngineErrorKind::CsvError(ref _err) => write!(f, "CSV parse error"),
-            EngineErrorKind::InvalidHeaders => write!(f, "Invalid headers encountered"),
+            EngineErrorKind::IoError(ref err) => write!(f, "I/O error: {}", err),
+            EngineErrorKind::InvalidHeaders(ref missing) => write!(
+                f,
+                "Invalid headers encountered, missing: {}",
+                missing.join(", ")
+            ),
+            EngineErrorKind::InvalidSnapshot => write!(f, "Invalid state snapshot"),
+            EngineErrorKind::InvalidJournal => write!(f, "Invalid journal"),
+            EngineErrorKind::InvalidCreditLimit(line) => {
+                write!(f, "Invalid credit limit at line {}", line)
+            }
             EngineErrorKind::NotEnoughAvailableCredit => {
                 write!(f, "Not enough available credit to withdraw")
             }
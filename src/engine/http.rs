@@ -0,0 +1,561 @@
+//! HTTP/JSON API over a shared [`Engine`], for tools posting transactions
+//! one at a time and reading balances back. Built with the `http` feature.
+//!
+//! - `POST /transactions` applies the transaction in the body, e.g.
+//!   `{"type":"deposit","client":1,"tx":1,"amount":"10.0"}`, and answers
+//!   with the client's account. A transfer names the client it credits
+//!   under `destination`;
+//! - `GET /clients` answers with every account;
+//! - `GET /clients/{id}` answers with a single account;
+//! - `GET /transactions/{tx}` answers with a stored transaction.
+//!
+//! Accounts are laid out as in the JSON report. Errors are answered with
+//! `{"error":{"kind":"<kind>","reason":"<reason>"}}`, `kind` and `reason`
+//! being the ones of the rejects log, or `request` along with what's wrong
+//! when the request itself doesn't make sense.
+
+use super::columns::ColumnMap;
+use super::error::{EngineError, EngineErrorKind, Result};
+use super::output::{self, write_json_account, OutputFormat, OutputOptions};
+use super::protocol::{Transaction, TransactionKind, TransactionState};
+use super::record::RecordError;
+use super::reject::describe;
+use super::server::lock;
+use super::DBError;
+use super::Engine;
+use csv::ByteRecord;
+use std::io::{self, Read};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Fields of a posted transaction, in the order of a csv row.
+const FIELDS: [&str; 5] = ["type", "client", "tx", "amount", "destination"];
+/// Largest body we read, a transaction being a few dozen bytes.
+const MAX_BODY: u64 = 4096;
+
+/// Serves `engine` over HTTP to every connection `listener` accepts. Only
+/// returns if the server fails to start.
+pub fn serve_http(listener: TcpListener, engine: Engine) -> io::Result<()> {
+    serve_http_shared(listener, Arc::new(Mutex::new(engine)))
+}
+
+/// Same as [`serve_http`], the engine remaining reachable by the caller.
+pub fn serve_http_shared(listener: TcpListener, engine: Arc<Mutex<Engine>>) -> io::Result<()> {
+    let server = Server::from_listener(listener, None).map_err(io::Error::other)?;
+    for mut request in server.incoming_requests() {
+        let (status, body) = route(&mut request, &engine);
+        let response = Response::from_data(body)
+            .with_status_code(status)
+            .with_header(json_header());
+        // The client going away only concerns its own request.
+        let _ = request.respond(response);
+    }
+    Ok(())
+}
+
+/// Answers a request, with its status code and JSON body.
+fn route(request: &mut Request, engine: &Mutex<Engine>) -> (u16, Vec<u8>) {
+    let segments: Vec<&str> = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let answered = match (request.method(), segments.as_slice()) {
+        (Method::Post, ["transactions"]) => match read_body(request) {
+            Some(body) => post_transaction(&body, engine),
+            None => Err(bad_request("InvalidBody")),
+        },
+        (Method::Get, ["clients"]) => get_clients(engine),
+        (Method::Get, ["clients", id]) => match id.parse() {
+            Ok(client) => get_client(client, engine),
+            Err(_) => Err(bad_request("InvalidClientId")),
+        },
+        (Method::Get, ["transactions", tx]) => match tx.parse() {
+            Ok(tx) => get_transaction(tx, engine),
+            Err(_) => Err(bad_request("InvalidTransactionId")),
+        },
+        (_, ["transactions"] | ["clients"] | ["clients", _] | ["transactions", _]) => {
+            Err(ApiError::Request(405, "MethodNotAllowed"))
+        }
+        _ => Err(ApiError::Request(404, "NotFound")),
+    };
+
+    match answered {
+        Ok(body) => (200, body),
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Body of a successful answer, or why the request failed.
+type Answer = std::result::Result<Vec<u8>, ApiError>;
+
+/// Why a request failed.
+enum ApiError {
+    /// The request itself is wrong, with the status code to answer.
+    Request(u16, &'static str),
+    /// The engine refused it.
+    Engine(EngineError),
+}
+
+impl From<EngineError> for ApiError {
+    fn from(err: EngineError) -> Self {
+        ApiError::Engine(err)
+    }
+}
+
+impl ApiError {
+    fn into_response(self) -> (u16, Vec<u8>) {
+        let (status, kind, reason) = match self {
+            ApiError::Request(status, reason) => (status, "request", reason.to_string()),
+            ApiError::Engine(err) => {
+                let (kind, reason) = describe(&err);
+                (status_of(&err), kind, reason)
+            }
+        };
+        let body = format!(
+            "{{\"error\":{{\"kind\":\"{}\",\"reason\":\"{}\"}}}}",
+            kind, reason
+        );
+        (status, body.into_bytes())
+    }
+}
+
+fn bad_request(reason: &'static str) -> ApiError {
+    ApiError::Request(400, reason)
+}
+
+/// Status code answering an engine error.
+fn status_of(err: &EngineError) -> u16 {
+    if err.is_fatal() {
+        return 500;
+    }
+    match err.kind() {
+        EngineErrorKind::RecordError(_) => 400,
+        EngineErrorKind::DBError(DBError::ClientNotFound | DBError::TransactionNotFound) => 404,
+        // The transaction doesn't fit the current state of the account.
+        EngineErrorKind::DBError(_) => 409,
+        _ => 500,
+    }
+}
+
+fn read_body(request: &mut Request) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY + 1)
+        .read_to_end(&mut body)
+        .ok()?;
+    (body.len() as u64 <= MAX_BODY).then_some(body)
+}
+
+fn post_transaction(body: &[u8], engine: &Mutex<Engine>) -> Answer {
+    let mut row = parse_transaction(body)?;
+
+    let mut engine = lock(engine);
+    match engine.submit_row(&mut row, &ColumnMap::default()) {
+        Ok(record) => Ok(account_json(record.client, &engine)?),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn get_clients(engine: &Mutex<Engine>) -> Answer {
+    let options = OutputOptions {
+        format: OutputFormat::Json,
+        ..OutputOptions::default()
+    };
+    let mut body = Vec::new();
+    // Copied out so the engine isn't held while writing.
+    let accounts: Vec<_> = lock(engine).accounts().collect();
+    output::write_report(accounts, &mut body, &options)?;
+    Ok(body)
+}
+
+fn get_client(client: u16, engine: &Mutex<Engine>) -> Answer {
+    Ok(account_json(client, &lock(engine))?)
+}
+
+fn get_transaction(tx: u32, engine: &Mutex<Engine>) -> Answer {
+    match lock(engine).transaction(tx)? {
+        Some(trx) => Ok(transaction_json(tx, &trx).into_bytes()),
+        None => Err(EngineError::from(DBError::TransactionNotFound).into()),
+    }
+}
+
+/// The client's account as a JSON object.
+fn account_json(client: u16, engine: &Engine) -> Result<Vec<u8>> {
+    let cas = engine.account(client).ok_or(DBError::ClientNotFound)?;
+    let mut body = Vec::new();
+    write_json_account(&mut body, client, &cas)?;
+    Ok(body)
+}
+
+fn transaction_json(tx: u32, trx: &Transaction) -> String {
+    let kind = match trx.kind() {
+        TransactionKind::Deposit => "deposit",
+        TransactionKind::Withdrawal => "withdrawal",
+        TransactionKind::Dispute => "dispute",
+        TransactionKind::Resolve => "resolve",
+        TransactionKind::Chargeback => "chargeback",
+        TransactionKind::Transfer => "transfer",
+    };
+    let state = match trx.state() {
+        TransactionState::Processed => "processed",
+        TransactionState::Disputed => "disputed",
+        TransactionState::Resolved => "resolved",
+        TransactionState::ChargedBack => "chargedback",
+    };
+    let destination = trx
+        .destination()
+        .map(|destination| format!(",\"destination\":{}", destination))
+        .unwrap_or_default();
+    format!(
+        "{{\"tx\":{},\"type\":\"{}\",\"client\":{}{},\"amount\":\"{}\",\"state\":\"{}\"}}",
+        tx,
+        kind,
+        trx.client_id(),
+        destination,
+        trx.amount(),
+        state
+    )
+}
+
+/// Reads a posted transaction, a flat JSON object, into a csv row so it
+/// goes through the same validation as the files. Values may be strings or
+/// numbers, unknown keys are ignored whatever their value and the amount and
+/// destination may be left out.
+fn parse_transaction(body: &[u8]) -> std::result::Result<ByteRecord, ApiError> {
+    let fields = parse_object(body).ok_or_else(|| bad_request("InvalidJson"))?;
+    if fields[..3].iter().any(Option::is_none) {
+        return Err(EngineError::from(RecordError::MissingField).into());
+    }
+    Ok(fields
+        .iter()
+        .map(|field| field.as_deref().unwrap_or_default())
+        .collect())
+}
+
+/// Picks the [`FIELDS`] out of a flat JSON object.
+fn parse_object(body: &[u8]) -> Option<[Option<String>; 5]> {
+    let body = std::str::from_utf8(body).ok()?.trim();
+    let mut rest = body.strip_prefix('{')?.strip_suffix('}')?.trim_start();
+    let mut fields: [Option<String>; 5] = Default::default();
+
+    while !rest.is_empty() {
+        let (key, after) = json_string(rest)?;
+        rest = after.trim_start().strip_prefix(':')?.trim_start();
+        let field = FIELDS.iter().position(|field| *field == key);
+        let (value, after) = match rest.chars().next()? {
+            '"' => json_string(rest)?,
+            // Only an unknown key may hold an object or an array.
+            '{' | '[' if field.is_none() => (String::new(), skip_nested(rest)?),
+            _ => {
+                let end = rest
+                    .find([',', ' ', '\n', '\t', '\r'])
+                    .unwrap_or(rest.len());
+                let literal = &rest[..end];
+                let boolean = literal == "true" || literal == "false";
+                if literal == "null" || (boolean && field.is_none()) {
+                    (String::new(), &rest[end..])
+                } else if !literal.is_empty()
+                    && literal
+                        .bytes()
+                        .all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b))
+                {
+                    (literal.to_string(), &rest[end..])
+                } else {
+                    return None;
+                }
+            }
+        };
+        if let Some(i) = field {
+            fields[i] = Some(value);
+        }
+        rest = after.trim_start();
+        match rest.strip_prefix(',') {
+            // Another member has to follow.
+            Some(after) if !after.trim_start().is_empty() => rest = after.trim_start(),
+            None if rest.is_empty() => (),
+            _ => return None,
+        }
+    }
+    Some(fields)
+}
+
+/// Reads a JSON string literal at the start of `s`, returning it unescaped
+/// along with what follows it.
+fn json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                c @ ('"' | '\\' | '/') => value.push(c),
+                'b' => value.push('\u{8}'),
+                'f' => value.push('\u{c}'),
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'u' => {
+                    let unit = hex_unit(&mut chars)?;
+                    let code = match unit {
+                        // A high surrogate, its low half has to follow.
+                        0xD800..=0xDBFF => {
+                            if chars.next()?.1 != '\\' || chars.next()?.1 != 'u' {
+                                return None;
+                            }
+                            let low = hex_unit(&mut chars)?;
+                            if !(0xDC00..=0xDFFF).contains(&low) {
+                                return None;
+                            }
+                            0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                        }
+                        unit => unit,
+                    };
+                    // A lone low surrogate isn't a char.
+                    value.push(char::from_u32(code)?);
+                }
+                _ => return None,
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Reads the four hex digits of a `\u` escape.
+fn hex_unit(chars: &mut std::str::CharIndices) -> Option<u32> {
+    let mut unit = 0;
+    for _ in 0..4 {
+        unit = unit * 16 + chars.next()?.1.to_digit(16)?;
+    }
+    Some(unit)
+}
+
+/// Skips a JSON object or array at the start of `s`, returning what follows
+/// it.
+fn skip_nested(s: &str) -> Option<&str> {
+    let mut open = Vec::new();
+    let mut rest = s;
+    loop {
+        let c = rest.chars().next()?;
+        match c {
+            '"' => {
+                rest = json_string(rest)?.1;
+                continue;
+            }
+            '{' => open.push('}'),
+            '[' => open.push(']'),
+            '}' | ']' => {
+                if open.pop()? != c {
+                    return None;
+                }
+                if open.is_empty() {
+                    return Some(&rest[1..]);
+                }
+            }
+            _ => (),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+}
+
+fn json_header() -> Header {
+    Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("a valid static header")
+}
+
+#[cfg(test)]
+fn request<B: AsRef<[u8]>>(
+    addr: std::net::SocketAddr,
+    method: &str,
+    path: &str,
+    body: B,
+) -> (u16, String) {
+    use std::io::Write;
+
+    let body = body.as_ref();
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+         Content-Length: {}\r\n\r\n",
+        method,
+        path,
+        body.len(),
+    )
+    .unwrap();
+    stream.write_all(body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap().to_string();
+    (status, body)
+}
+
+#[test]
+fn test_http_api() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let engine = Arc::new(Mutex::new(Engine::new()));
+    let shared = Arc::clone(&engine);
+    std::thread::spawn(move || serve_http_shared(listener, shared));
+
+    let post = |body: &str| request(addr, "POST", "/transactions", body);
+    assert_eq!(
+        post(r#"{"type":"deposit","client":1,"tx":1,"amount":"10.5"}"#),
+        (
+            200,
+            r#"{"client":1,"available":"10.5000","held":"0.0000","total":"10.5000","locked":false}"#
+                .to_string()
+        )
+    );
+    assert_eq!(
+        post(r#"{ "type": "withdrawal", "client": 1, "tx": 2, "amount": 20 }"#),
+        (
+            409,
+            r#"{"error":{"kind":"db","reason":"NotEnoughAvailableCredit"}}"#.to_string()
+        )
+    );
+    assert_eq!(post(r#"{"type":"dispute","client":1,"tx":1}"#).0, 200);
+    assert_eq!(
+        post(r#"{"type":"dispute","client":1}"#),
+        (
+            400,
+            r#"{"error":{"kind":"record","reason":"MissingField"}}"#.to_string()
+        )
+    );
+    assert_eq!(
+        post("type,client,tx,amount"),
+        (
+            400,
+            r#"{"error":{"kind":"request","reason":"InvalidJson"}}"#.to_string()
+        )
+    );
+
+    assert_eq!(
+        request(addr, "GET", "/clients/1", ""),
+        (
+            200,
+            r#"{"client":1,"available":"0.0000","held":"10.5000","total":"10.5000","locked":false}"#
+                .to_string()
+        )
+    );
+    assert_eq!(
+        request(addr, "GET", "/clients/2", ""),
+        (
+            404,
+            r#"{"error":{"kind":"db","reason":"ClientNotFound"}}"#.to_string()
+        )
+    );
+    let (status, clients) = request(addr, "GET", "/clients", "");
+    assert_eq!(status, 200);
+    assert!(clients.starts_with("[\n  {\"client\":1,"));
+    assert_eq!(
+        request(addr, "GET", "/transactions/1", ""),
+        (
+            200,
+            r#"{"tx":1,"type":"deposit","client":1,"amount":"10.5000","state":"disputed"}"#
+                .to_string()
+        )
+    );
+    assert_eq!(request(addr, "GET", "/transactions/2", "").0, 404);
+    assert_eq!(request(addr, "GET", "/transactions/x", "").0, 400);
+
+    assert_eq!(
+        post(r#"{"type":"deposit","client":3,"tx":3,"amount":5}"#).0,
+        200
+    );
+    assert_eq!(
+        post(r#"{"type":"transfer","client":3,"tx":4,"amount":2,"destination":4}"#).0,
+        200
+    );
+    assert_eq!(
+        request(addr, "GET", "/transactions/4", ""),
+        (
+            200,
+            r#"{"tx":4,"type":"transfer","client":3,"destination":4,"amount":"2.0000","state":"processed"}"#
+                .to_string()
+        )
+    );
+    assert_eq!(
+        request(
+            addr,
+            "POST",
+            "/transactions",
+            b"{\"type\":\"deposit\",\"client\":\xff,\"tx\":9,\"amount\":1}"
+        ),
+        (
+            400,
+            r#"{"error":{"kind":"request","reason":"InvalidJson"}}"#.to_string()
+        )
+    );
+    assert_eq!(request(addr, "DELETE", "/clients/1", "").0, 405);
+    assert_eq!(request(addr, "GET", "/", "").0, 404);
+
+    // Rejected transactions are counted as the rows of an input, bodies
+    // refused before reaching the engine aside.
+    assert_eq!(lock(&engine).tally.rejected, 1);
+}
+
+#[test]
+fn test_parse_transaction() {
+    let row =
+        parse_transaction(br#"{"tx":3,"note":"a \"quoted\" \u0041","client":2,"type":"resolve"}"#)
+            .ok()
+            .unwrap();
+    assert_eq!(row, ByteRecord::from(vec!["resolve", "2", "3", "", ""]));
+
+    let row = parse_transaction(br#"{"type":"deposit","client":1,"tx":1,"amount":null}"#)
+        .ok()
+        .unwrap();
+    assert_eq!(row, ByteRecord::from(vec!["deposit", "1", "1", "", ""]));
+
+    let row = parse_transaction(
+        br#"{"type":"transfer","client":1,"tx":2,"amount":"1.5","destination":3}"#,
+    )
+    .ok()
+    .unwrap();
+    assert_eq!(
+        row,
+        ByteRecord::from(vec!["transfer", "1", "2", "1.5", "3"])
+    );
+
+    // Unknown keys are skipped, whatever they hold.
+    let row = parse_transaction(
+        br#"{"meta":{"tags":["a","}"],"n":{}},"type":"dispute","ok":true,"client":1,"tx":4}"#,
+    )
+    .ok()
+    .unwrap();
+    assert_eq!(row, ByteRecord::from(vec!["dispute", "1", "4", "", ""]));
+
+    assert_eq!(
+        json_string(r#""\b\f\/ \ud83d\ude00 \u00e9" rest"#),
+        Some(("\u{8}\u{c}/ \u{1f600} \u{e9}".to_string(), " rest"))
+    );
+
+    for invalid in [
+        &br#"{"type":"deposit""#[..],
+        br#"{"type":deposit}"#,
+        br#"{"type":"deposit",}"#,
+        br#"{"type" "deposit"}"#,
+        br#"{"type":"deposit","note":"\q"}"#,
+        br#"{"type":"deposit","note":"\ud83d"}"#,
+        br#"{"type":"deposit","note":"\ude00"}"#,
+        br#"{"type":"deposit","note":"\u00g1"}"#,
+        br#"{"type":"deposit","meta":{"a":[1}]}"#,
+        br#"{"type":["deposit"],"client":1,"tx":1}"#,
+        b"{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.\xff\"}",
+    ] {
+        assert!(matches!(
+            parse_transaction(invalid),
+            Err(ApiError::Request(400, "InvalidJson"))
+        ));
+    }
+}
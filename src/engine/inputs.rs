@@ -0,0 +1,617 @@
+//! Several inputs processed in a single run, either one after the other or
+//! merged on a sequence column, each of them getting its own summary.
+
+use super::columns::{header_matches, Rows};
+use super::compression::open_input;
+use super::error::{EngineError, EngineErrorKind, Result};
+use super::record::RecordError;
+use super::Engine;
+use csv::ByteRecord;
+use std::cmp::Ordering;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// What processing an input came to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputSummary {
+    pub path: PathBuf,
+    /// Rows read from the input, the ones a resumed run skips aside.
+    pub rows: u64,
+    /// How many of those rows were rejected.
+    pub rejected: u64,
+}
+
+/// Running count of the rows processed.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Tally {
+    pub rows: u64,
+    pub rejected: u64,
+}
+
+impl Tally {
+    /// What was counted since `before`.
+    fn since(self, before: Tally) -> Tally {
+        Tally {
+            rows: self.rows - before.rows,
+            rejected: self.rejected - before.rejected,
+        }
+    }
+
+    fn summary<P: AsRef<Path>>(self, path: P) -> InputSummary {
+        InputSummary {
+            path: path.as_ref().to_path_buf(),
+            rows: self.rows,
+            rejected: self.rejected,
+        }
+    }
+}
+
+/// An input being merged, along with its next row.
+struct Input {
+    rows: Rows<Box<dyn Read>>,
+    /// Index of the sequence column, when merging on one.
+    key_index: Option<usize>,
+    head: Option<ByteRecord>,
+    /// Sequence value of the next row.
+    key: Vec<u8>,
+    /// Whether the sequence values compare as numbers, which is decided
+    /// once for the whole run.
+    numeric: bool,
+    /// Whether the next row holds a sequence value that isn't a number,
+    /// while they compare as numbers.
+    bad_key: bool,
+    tally: Tally,
+}
+
+impl Input {
+    /// Reads the next row in.
+    fn advance(&mut self) -> Result<()> {
+        let mut row = ByteRecord::new();
+        if !self.rows.read(&mut row)? {
+            self.head = None;
+            return Ok(());
+        }
+        // A row lacking a value stays right after the previous one, and so
+        // does a row whose value doesn't compare, to be rejected.
+        self.bad_key = false;
+        if let Some(value) = self.key_index.and_then(|i| row.get(i)) {
+            let value = value.trim_ascii();
+            if self.numeric && !value.is_empty() && number(value).is_none() {
+                self.bad_key = true;
+            } else if !value.is_empty() {
+                self.key = value.to_vec();
+            }
+        }
+        self.head = Some(row);
+        Ok(())
+    }
+}
+
+impl Engine {
+    /// Processes the csv files one after the other, in the given order, each
+    /// over `shards` threads as [`Engine::process_reader_parallel`] does.
+    /// Compressed files are decompressed on the fly.
+    ///
+    /// With a journal, the rows are processed sequentially and numbered
+    /// across all the files, so a crashed run resumes with the same list of
+    /// files, another list being refused.
+    pub fn process_files<P: AsRef<Path>>(
+        &mut self,
+        paths: &[P],
+        shards: usize,
+    ) -> Result<Vec<InputSummary>> {
+        if self.journal.is_some() {
+            return self.process_inputs(paths, None);
+        }
+
+        let mut summaries = Vec::with_capacity(paths.len());
+        if shards <= 1 {
+            for path in paths {
+                self.reject_input(paths, path);
+                let before = self.tally;
+                self.process_reader(open_input(path)?)?;
+                summaries.push(self.tally.since(before).summary(path));
+            }
+            return Ok(summaries);
+        }
+
+        // The shards are only split and merged once for all the files.
+        let mut sharded = self.shard(shards)?;
+        for path in paths {
+            self.reject_input(paths, path);
+            let before = self.tally;
+            self.process_sharded(open_input(path)?, &mut sharded)?;
+            summaries.push(self.tally.since(before).summary(path));
+        }
+        self.unshard(sharded)?;
+        Ok(summaries)
+    }
+
+    /// Processes the csv files as a single stream, merged on the values of
+    /// their `column`, a timestamp or a sequence number, each file being
+    /// sorted on it already.
+    ///
+    /// The next row is taken from the file holding the lowest value, ties
+    /// going to the file given first. Values compare as numbers if the first
+    /// one of every file is a number, a later row holding something else
+    /// being rejected, and as text otherwise, which suits ISO 8601
+    /// timestamps. A row lacking a value keeps its place right after the
+    /// previous row of its file. The rows are processed sequentially and
+    /// numbered across the files for the journal.
+    pub fn process_merged<P: AsRef<Path>>(
+        &mut self,
+        paths: &[P],
+        column: &str,
+    ) -> Result<Vec<InputSummary>> {
+        self.process_inputs(paths, Some(column))
+    }
+
+    fn process_inputs<P: AsRef<Path>>(
+        &mut self,
+        paths: &[P],
+        column: Option<&str>,
+    ) -> Result<Vec<InputSummary>> {
+        self.bind_journal(paths, column)?;
+        let mut inputs = Vec::with_capacity(paths.len());
+        for path in paths {
+            let rows = self.csv_reader(open_input(path)?)?;
+            let key_index = match column {
+                Some(column) => Some(key_index(&rows, column)?),
+                None => None,
+            };
+            let mut input = Input {
+                rows,
+                key_index,
+                head: None,
+                key: Vec::new(),
+                numeric: false,
+                bad_key: false,
+                tally: Tally::default(),
+            };
+            input.advance()?;
+            inputs.push(input);
+        }
+        // The values compare as numbers if the first one of every input is
+        // one, as text otherwise.
+        let numeric = inputs
+            .iter()
+            .all(|input| input.key.is_empty() || number(&input.key).is_some());
+        for input in inputs.iter_mut() {
+            input.numeric = numeric;
+        }
+
+        let mut offset = 0;
+        let mut current = None;
+        while let Some(next) = next_input(&inputs) {
+            if current != Some(next) {
+                self.reject_input(paths, &paths[next]);
+                current = Some(next);
+            }
+            let input = &mut inputs[next];
+            let mut row = input.head.take().unwrap_or_default();
+            let columns = input.rows.columns;
+            let bad_key = input.bad_key;
+            input.advance()?;
+
+            offset += 1;
+            let before = self.tally;
+            if bad_key {
+                self.reject_row(row, offset, RecordError::Parse.into())?;
+            } else {
+                self.process_row(&mut row, offset, &columns)?;
+            }
+            let counted = self.tally.since(before);
+            inputs[next].tally.rows += counted.rows;
+            inputs[next].tally.rejected += counted.rejected;
+        }
+        self.finish_input()?;
+
+        Ok(paths
+            .iter()
+            .zip(inputs)
+            .map(|(path, input)| input.tally.summary(path))
+            .collect())
+    }
+
+    /// Rejects a row without applying it, `offset` numbering it as
+    /// [`Engine::process_row`] does.
+    fn reject_row(&mut self, row: ByteRecord, offset: u64, err: EngineError) -> Result<()> {
+        // Already rejected before the previous run went down.
+        if self.resume_after.is_some_and(|after| offset <= after) {
+            return Ok(());
+        }
+        self.tally.rows += 1;
+        let raw = self.rejects.as_ref().map(|_| row);
+        self.reject(raw, err)
+    }
+
+    /// Has the rejects log name `path` as the input of its next entries,
+    /// when the run reads several of the `paths`.
+    fn reject_input<P: AsRef<Path>>(&mut self, paths: &[P], path: &P) {
+        if let (Some(log), true) = (self.rejects.as_mut(), paths.len() > 1) {
+            log.set_input(path.as_ref());
+        }
+    }
+}
+
+/// Index of the sequence `column` in the header row of `rows`.
+fn key_index<R>(rows: &Rows<R>, column: &str) -> Result<usize> {
+    rows.headers
+        .iter()
+        .flat_map(|headers| headers.iter())
+        .position(|header| header_matches(header, column))
+        .ok_or_else(|| EngineError::new(EngineErrorKind::InvalidHeaders(vec![column.to_string()])))
+}
+
+/// Which input the next row comes from, if any is left.
+fn next_input(inputs: &[Input]) -> Option<usize> {
+    let mut next: Option<usize> = None;
+    for (i, input) in inputs.iter().enumerate() {
+        if input.head.is_none() {
+            continue;
+        }
+        match next {
+            Some(n) if input.key_index.is_none() => return Some(n),
+            Some(n) if cmp_keys(&input.key, &inputs[n].key, input.numeric) != Ordering::Less => (),
+            _ => next = Some(i),
+        }
+    }
+    next
+}
+
+/// Compares two sequence values, as numbers or as text for the whole run so
+/// the order is a total one. A missing value comes first.
+fn cmp_keys(a: &[u8], b: &[u8], numeric: bool) -> Ordering {
+    if numeric {
+        number(a).cmp(&number(b))
+    } else {
+        a.cmp(b)
+    }
+}
+
+/// A sequence value as a number, if it is one.
+fn number(key: &[u8]) -> Option<u64> {
+    std::str::from_utf8(key).ok()?.parse().ok()
+}
+
+/// Expands the inputs given on the command line: a directory stands for the
+/// files it holds, and a file name with a `*` or a `?` for the files it
+/// matches, as a shell would. Both are sorted by name, hidden files left
+/// out. Anything else is taken as is.
+pub fn expand_inputs<S: AsRef<str>>(args: &[S]) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for arg in args {
+        let path = Path::new(arg.as_ref());
+        let pattern = path.file_name().and_then(|name| name.to_str());
+
+        if path.is_dir() {
+            paths.extend(list_files(path, |_| true)?);
+        } else if let Some(pattern) = pattern.filter(|name| name.contains(['*', '?'])) {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let matched = list_files(dir, |name| wildcard_match(pattern, name))?;
+            if matched.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no file matches {}", arg.as_ref()),
+                ));
+            }
+            paths.extend(matched);
+        } else {
+            paths.push(path.to_path_buf());
+        }
+    }
+    Ok(paths)
+}
+
+/// The files of `dir` whose name passes `keep`, sorted by name.
+fn list_files(dir: &Path, keep: impl Fn(&str) -> bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else { continue };
+        if !name.starts_with('.') && entry.file_type()?.is_file() && keep(name) {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether `name` matches `pattern`, `*` standing for any run of
+/// characters and `?` for a single one.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where to pick up from when the last `*` has to swallow one more.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, swallowed)) => {
+                    p = star + 1;
+                    n = swallowed + 1;
+                    backtrack = Some((star, swallowed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+fn write_inputs(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = super::temp_path(name);
+    fs::create_dir_all(&dir).unwrap();
+    for (file, content) in files {
+        fs::write(dir.join(file), content).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn test_wildcard_match() {
+    assert!(wildcard_match("*.csv", "card.csv"));
+    assert!(wildcard_match("2024-??-*.csv", "2024-01-card.csv"));
+    assert!(wildcard_match("*a*b", "xxaxxab"));
+    assert!(!wildcard_match("*.csv", "card.csv.gz"));
+    assert!(!wildcard_match("2024-??-*.csv", "2024-1-card.csv"));
+    assert!(wildcard_match("*", ""));
+}
+
+#[test]
+fn test_expand_inputs() {
+    let dir = write_inputs(
+        "expand",
+        &[
+            ("b.csv", ""),
+            ("a.csv", ""),
+            ("c.txt", ""),
+            (".hidden.csv", ""),
+        ],
+    );
+
+    let glob = format!("{}/*.csv", dir.display());
+    assert_eq!(
+        expand_inputs(&[glob.as_str()]).unwrap(),
+        vec![dir.join("a.csv"), dir.join("b.csv")]
+    );
+    assert_eq!(
+        expand_inputs(&[dir.to_str().unwrap()]).unwrap(),
+        vec![dir.join("a.csv"), dir.join("b.csv"), dir.join("c.txt")]
+    );
+    assert_eq!(
+        expand_inputs(&["some/file.csv"]).unwrap(),
+        vec![PathBuf::from("some/file.csv")]
+    );
+    let nothing = format!("{}/*.json", dir.display());
+    assert!(expand_inputs(&[nothing.as_str()]).is_err());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_process_files() {
+    let dir = write_inputs(
+        "files",
+        &[
+            ("card.csv", "type,client,tx,amount\ndeposit,1,1,10.0\n"),
+            (
+                "wire.csv",
+                "type,client,tx,amount\nwithdrawal,1,2,4.0\nteleport,1,3,1.0\n",
+            ),
+        ],
+    );
+    let paths = [dir.join("card.csv"), dir.join("wire.csv")];
+
+    let mut engine = Engine::new();
+    let summaries = engine.process_files(&paths, 1).unwrap();
+    assert_eq!(
+        summaries,
+        vec![
+            InputSummary {
+                path: paths[0].clone(),
+                rows: 1,
+                rejected: 0,
+            },
+            InputSummary {
+                path: paths[1].clone(),
+                rows: 2,
+                rejected: 1,
+            },
+        ]
+    );
+    assert_eq!(engine.account(1).unwrap().total(), "6.0".parse().unwrap());
+
+    // Rows rejected by the shards count as well, without a rejects log.
+    let mut parallel = Engine::new();
+    assert_eq!(parallel.process_files(&paths, 2).unwrap(), summaries);
+
+    // Entries of the rejects log name the input they come from.
+    let expected = format!(
+        "input,line,byte,kind,reason,row\n{},3,41,record,Parse,\"teleport,1,3,1.0\"\n",
+        paths[1].display()
+    );
+    for shards in [1, 2] {
+        let log = dir.join("rejects.csv");
+        let mut engine = Engine::new();
+        engine.set_reject_log(super::RejectLog::create(&log).unwrap());
+        engine.process_files(&paths, shards).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), expected);
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_process_merged() {
+    let dir = write_inputs(
+        "merged",
+        &[
+            (
+                "card.csv",
+                "type,client,tx,amount,seq\n\
+                 deposit,1,1,10.0,1\n\
+                 withdrawal,1,3,8.0,10\n\
+                 withdrawal,1,4,1.0,\n",
+            ),
+            (
+                "wire.csv",
+                "type,client,tx,amount,seq\n\
+                 withdrawal,1,2,5.0,2\n\
+                 teleport,1,5,1.0,9\n",
+            ),
+        ],
+    );
+    let paths = [dir.join("card.csv"), dir.join("wire.csv")];
+
+    // The wire withdrawal comes in second, leaving too little for the card
+    // one of 8. The next card row, lacking a value, follows it right away.
+    let mut engine = Engine::new();
+    let summaries = engine.process_merged(&paths, "seq").unwrap();
+    assert_eq!(
+        summaries,
+        vec![
+            InputSummary {
+                path: paths[0].clone(),
+                rows: 3,
+                rejected: 1,
+            },
+            InputSummary {
+                path: paths[1].clone(),
+                rows: 2,
+                rejected: 1,
+            },
+        ]
+    );
+    assert_eq!(engine.account(1).unwrap().total(), "4.0".parse().unwrap());
+
+    // Taken in turn, the card withdrawals both go through.
+    let mut engine = Engine::new();
+    engine.process_files(&paths, 1).unwrap();
+    assert_eq!(engine.account(1).unwrap().total(), "1.0".parse().unwrap());
+
+    let mut engine = Engine::new();
+    let err = engine.process_merged(&paths, "timestamp").unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        EngineErrorKind::InvalidHeaders(missing) if missing == &["timestamp"]
+    ));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_process_merged_mixed_values() {
+    let dir = write_inputs(
+        "mixed",
+        &[
+            (
+                "card.csv",
+                "type,client,tx,amount,seq\n\
+                 deposit,1,1,10.0,1\n\
+                 deposit,1,3,1.0,late\n\
+                 withdrawal,1,4,8.0,10\n",
+            ),
+            (
+                "wire.csv",
+                "type,client,tx,amount,seq\n\
+                 withdrawal,1,2,5.0,9\n",
+            ),
+        ],
+    );
+    let paths = [dir.join("card.csv"), dir.join("wire.csv")];
+
+    // The first values are numbers, so "late" is rejected rather than
+    // compared as text, and 9 still comes before 10.
+    let mut engine = Engine::new();
+    let summaries = engine.process_merged(&paths, "seq").unwrap();
+    assert_eq!(summaries[0].rejected, 2);
+    assert_eq!(summaries[1].rejected, 0);
+    assert_eq!(engine.account(1).unwrap().total(), "5.0".parse().unwrap());
+
+    // With a first value that isn't a number, all of them compare as text,
+    // "10" coming before "9a" and leaving too little for the wire one.
+    fs::write(
+        &paths[1],
+        "type,client,tx,amount,seq\n\
+         withdrawal,1,2,5.0,9a\n",
+    )
+    .unwrap();
+    fs::write(
+        &paths[0],
+        "type,client,tx,amount,seq\n\
+         deposit,1,1,10.0,1\n\
+         withdrawal,1,4,8.0,10\n",
+    )
+    .unwrap();
+    let mut engine = Engine::new();
+    let summaries = engine.process_merged(&paths, "seq").unwrap();
+    assert_eq!(summaries[0].rejected, 0);
+    assert_eq!(summaries[1].rejected, 1);
+    assert_eq!(engine.account(1).unwrap().total(), "2.0".parse().unwrap());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_resume_other_inputs() {
+    use super::Journal;
+
+    let dir = write_inputs(
+        "resume-inputs",
+        &[
+            ("a.csv", "type,client,tx,amount,seq\ndeposit,1,1,10.0,2\n"),
+            ("b.csv", "type,client,tx,amount,seq\nwithdrawal,1,2,4.0,1\n"),
+        ],
+    );
+    let paths = [dir.join("a.csv"), dir.join("b.csv")];
+    let journal = dir.join("run.journal");
+    let resumed = || {
+        let mut engine = Engine::new();
+        engine
+            .set_journal(Journal::open(&journal, 1).unwrap())
+            .unwrap();
+        engine
+    };
+
+    // The run goes down once both files are processed, before its snapshot
+    // is saved.
+    resumed().process_files(&paths, 1).unwrap();
+
+    // Numbered another way, the rows the journal covers can't be told.
+    let refused = [
+        resumed().process_files(&paths[..1], 1).map(|_| ()),
+        resumed().process_merged(&paths, "seq").map(|_| ()),
+        resumed().process(&paths[0]),
+        resumed().process_reader("deposit,1,1,10.0\n".as_bytes()),
+    ];
+    for result in refused {
+        assert!(matches!(
+            result.unwrap_err().kind(),
+            EngineErrorKind::InvalidJournal
+        ));
+    }
+
+    let mut engine = resumed();
+    let summaries = engine.process_files(&paths, 1).unwrap();
+    assert!(summaries.iter().all(|summary| summary.rows == 0));
+    assert_eq!(engine.account(1).unwrap().total(), "6.0".parse().unwrap());
+
+    fs::remove_dir_all(dir).unwrap();
+}
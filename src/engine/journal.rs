@@ -0,0 +1,420 @@
+//! Write-ahead journal of the changes applied to the databases, so a run
+//! that died halfway through can be resumed right after the last row it
+//! applied.
+//!
+//! The journal starts with the `KCTJ` magic, the format version on two
+//! bytes, the generation of the snapshot it applies on top of on eight bytes
+//! and the fingerprint of the inputs it covers on eight more, followed by
+//! fixed size entries, integers being little endian:
+//!
+//! | bytes  | content                                            |
+//! |--------|----------------------------------------------------|
+//! | 0..8   | row number, counted from 1 across the inputs       |
+//! | 8..10  | client id                                          |
+//! | 10..14 | transaction id                                     |
+//! | 14     | flags: 1 account, 2 account locked, 4 transaction, |
+//! |        | 8 counterpart, 16 counterpart locked               |
+//! | 15..39 | account available, held and total                  |
+//! | 39..55 | transaction slot, laid out as in the index file    |
+//! | 55..57 | counterpart client id                              |
+//! | 57..81 | counterpart available, held and total              |
+//! | 81..85 | FNV-1a checksum of the bytes above                 |
+//!
+//! An entry holds the account and the transaction as they are once the row
+//! is applied rather than the differences, so replaying an entry twice is
+//! harmless. The counterpart is the other account a transfer, or a dispute
+//! over one, changes along in the same entry.
+//!
+//! A journal of an older generation is left by a run that went down right
+//! after saving the snapshot, which covers it already, so it's dropped. The
+//! rows being numbered across the inputs, a run only resumes from a journal
+//! if it processes the same inputs, in the same order, see [`fingerprint`].
+
+use super::amount::Amount;
+use super::db::{
+    client::ClientAccountState, decode_transaction, encode_transaction, Storage, SLOT_SIZE,
+};
+use super::error::{EngineError, EngineErrorKind, Result};
+use super::protocol::Transaction;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 4] = *b"KCTJ";
+/// Bumped whenever the layout changes.
+const VERSION: u16 = 4;
+const HEADER_SIZE: u64 = 22;
+/// Where the inputs fingerprint sits in the header.
+const INPUTS_AT: u64 = 14;
+const ENTRY_SIZE: usize = 85;
+/// How many entries are written between two syncs when none is given.
+pub const DEFAULT_SYNC_EVERY: usize = 1024;
+
+const ACCOUNT: u8 = 1;
+const LOCKED: u8 = 2;
+const TRANSACTION: u8 = 4;
+const COUNTERPART: u8 = 8;
+const COUNTERPART_LOCKED: u8 = 16;
+
+/// A change applied to the databases by a single row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct JournalEntry {
+    /// Number of the row, counted from 1 across the inputs.
+    pub offset: u64,
+    pub client: u16,
+    pub tx: u32,
+    /// The client's account once the row is applied.
+    pub account: Option<ClientAccountState>,
+    /// The transaction once the row is applied.
+    pub transaction: Option<Transaction>,
+    /// The other side of a transfer once the row is applied.
+    pub counterpart: Option<(u16, ClientAccountState)>,
+}
+
+impl JournalEntry {
+    fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0..8].copy_from_slice(&self.offset.to_le_bytes());
+        entry[8..10].copy_from_slice(&self.client.to_le_bytes());
+        entry[10..14].copy_from_slice(&self.tx.to_le_bytes());
+        if let Some(cas) = self.account {
+            entry[14] |= ACCOUNT;
+            if cas.locked() {
+                entry[14] |= LOCKED;
+            }
+            encode_account(&cas, &mut entry[15..39]);
+        }
+        if let Some(trx) = self.transaction {
+            entry[14] |= TRANSACTION;
+            entry[39..55].copy_from_slice(&encode_transaction(&trx));
+        }
+        if let Some((client, cas)) = self.counterpart {
+            entry[14] |= COUNTERPART;
+            if cas.locked() {
+                entry[14] |= COUNTERPART_LOCKED;
+            }
+            entry[55..57].copy_from_slice(&client.to_le_bytes());
+            encode_account(&cas, &mut entry[57..81]);
+        }
+        let checksum = fnv1a(&entry[..81]);
+        entry[81..85].copy_from_slice(&checksum.to_le_bytes());
+        entry
+    }
+
+    /// Reads back an entry, None meaning it's torn or damaged.
+    fn decode(entry: &[u8; ENTRY_SIZE]) -> Option<Self> {
+        if fnv1a(&entry[..81]).to_le_bytes() != entry[81..85] {
+            return None;
+        }
+        let flags = entry[14];
+        let account =
+            (flags & ACCOUNT != 0).then(|| decode_account(&entry[15..39], flags & LOCKED != 0));
+        let transaction = if flags & TRANSACTION != 0 {
+            let mut slot = [0u8; SLOT_SIZE];
+            slot.copy_from_slice(&entry[39..55]);
+            Some(decode_transaction(&slot).ok()??)
+        } else {
+            None
+        };
+        let counterpart = (flags & COUNTERPART != 0).then(|| {
+            (
+                u16::from_le_bytes([entry[55], entry[56]]),
+                decode_account(&entry[57..81], flags & COUNTERPART_LOCKED != 0),
+            )
+        });
+
+        Some(Self {
+            offset: u64::from_le_bytes(entry[0..8].try_into().ok()?),
+            client: u16::from_le_bytes([entry[8], entry[9]]),
+            tx: u32::from_le_bytes(entry[10..14].try_into().ok()?),
+            account,
+            transaction,
+            counterpart,
+        })
+    }
+
+    /// Applies the entry to `db`.
+    fn apply(&self, db: &mut dyn Storage) -> Result<()> {
+        if let Some(cas) = self.account {
+            db.set_client(self.client, cas);
+        }
+        if let Some((client, cas)) = self.counterpart {
+            db.set_client(client, cas);
+        }
+        if let Some(trx) = self.transaction {
+            db.put_transaction(self.tx, trx)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append-only journal file. Entries are synced to disk in batches, a crash
+/// losing at most the last batch: its rows are processed again on recovery.
+pub struct Journal {
+    file: BufWriter<File>,
+    /// Entries written since the last sync.
+    pending: usize,
+    sync_every: usize,
+    /// Fingerprint of the inputs the entries cover.
+    inputs: u64,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if needed. Entries are synced
+    /// to disk every `sync_every` of them.
+    pub fn open<P: AsRef<Path>>(path: P, sync_every: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        Ok(Self {
+            file: BufWriter::new(file),
+            pending: 0,
+            sync_every: sync_every.max(1),
+            inputs: 0,
+        })
+    }
+
+    /// Applies every entry of the journal to `db`, loaded from the snapshot
+    /// of the given `generation`, and returns the offset of the last row
+    /// they cover, if any. A torn entry at the end, left by a crash, is
+    /// dropped. So is the whole journal if the snapshot is a newer one, and
+    /// an older snapshot is refused.
+    pub(crate) fn replay(&mut self, db: &mut dyn Storage, generation: u64) -> Result<Option<u64>> {
+        let file = self.file.get_mut();
+        let len = file.seek(SeekFrom::End(0))?;
+        if len == 0 {
+            self.reset(generation)?;
+            return Ok(None);
+        }
+
+        file.seek(SeekFrom::Start(0))?;
+        let mut rdr = BufReader::new(&*file);
+        let mut header = [0u8; HEADER_SIZE as usize];
+        if rdr.read_exact(&mut header).is_err()
+            || header[..4] != MAGIC
+            || header[4..6] != VERSION.to_le_bytes()
+        {
+            return Err(EngineError::new(EngineErrorKind::InvalidJournal));
+        }
+        let written_over = u64::from_le_bytes(header[6..14].try_into().unwrap_or_default());
+        if written_over < generation {
+            self.reset(generation)?;
+            return Ok(None);
+        } else if written_over > generation {
+            return Err(EngineError::new(EngineErrorKind::InvalidJournal));
+        }
+        self.inputs = u64::from_le_bytes(header[14..].try_into().unwrap_or_default());
+
+        let mut last = None;
+        let mut valid = HEADER_SIZE;
+        let mut entry = [0u8; ENTRY_SIZE];
+        loop {
+            match rdr.read_exact(&mut entry) {
+                Ok(()) => (),
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+            match JournalEntry::decode(&entry) {
+                Some(entry) => {
+                    entry.apply(db)?;
+                    last = Some(entry.offset);
+                    valid += ENTRY_SIZE as u64;
+                }
+                None => break,
+            }
+        }
+
+        // Appends go right after the last sound entry.
+        let file = self.file.get_mut();
+        file.set_len(valid)?;
+        file.seek(SeekFrom::Start(valid))?;
+        Ok(last)
+    }
+
+    /// Fingerprint of the inputs the entries cover, see [`fingerprint`].
+    pub(crate) fn inputs(&self) -> u64 {
+        self.inputs
+    }
+
+    /// Writes down the fingerprint of the inputs the next entries cover.
+    pub(crate) fn set_inputs(&mut self, inputs: u64) -> Result<()> {
+        if inputs == self.inputs {
+            return Ok(());
+        }
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(INPUTS_AT))?;
+        file.write_all(&inputs.to_le_bytes())?;
+        file.sync_data()?;
+        file.seek(SeekFrom::End(0))?;
+        self.inputs = inputs;
+        Ok(())
+    }
+
+    /// Appends an entry, syncing the batch to disk once it's full.
+    pub(crate) fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        self.file.write_all(&entry.encode())?;
+        self.pending += 1;
+        if self.pending >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the entries written so far to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    /// Empties the journal, once the snapshot of the given `generation`
+    /// covers what it holds.
+    pub fn reset(&mut self, generation: u64) -> Result<()> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        file.write_all(&generation.to_le_bytes())?;
+        file.write_all(&0u64.to_le_bytes())?;
+        file.sync_data()?;
+        self.pending = 0;
+        self.inputs = 0;
+        Ok(())
+    }
+}
+
+/// Writes the available, held and total amounts of `cas` to `bytes`.
+fn encode_account(cas: &ClientAccountState, bytes: &mut [u8]) {
+    bytes[0..8].copy_from_slice(&cas.available().raw().to_le_bytes());
+    bytes[8..16].copy_from_slice(&cas.held().raw().to_le_bytes());
+    bytes[16..24].copy_from_slice(&cas.total().raw().to_le_bytes());
+}
+
+fn decode_account(bytes: &[u8], locked: bool) -> ClientAccountState {
+    ClientAccountState::from_parts(
+        amount(&bytes[0..8]),
+        amount(&bytes[8..16]),
+        amount(&bytes[16..24]),
+        locked,
+    )
+}
+
+fn amount(bytes: &[u8]) -> Amount {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(bytes);
+    Amount::from_raw(i64::from_le_bytes(raw))
+}
+
+/// Fingerprint of the inputs of a run: their paths, in order, and the
+/// column they are merged on, if any. A reader given as is has no path.
+pub(crate) fn fingerprint<P: AsRef<Path>>(paths: &[P], merged_on: Option<&str>) -> u64 {
+    let mut bytes = Vec::new();
+    for path in paths {
+        bytes.extend_from_slice(path.as_ref().to_string_lossy().as_bytes());
+        bytes.push(0);
+    }
+    if let Some(column) = merged_on {
+        bytes.push(1);
+        bytes.extend_from_slice(column.as_bytes());
+    }
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// 32 bits FNV-1a hash, enough to tell a torn entry apart.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, b| {
+        (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[test]
+fn test_journal_entry_roundtrip() {
+    use super::protocol::TransactionKind;
+
+    let mut cas = ClientAccountState::new();
+    cas.add("3.5".parse().unwrap()).unwrap();
+    cas.lock();
+    let entry = JournalEntry {
+        offset: 42,
+        client: 7,
+        tx: u32::MAX,
+        account: Some(cas),
+        transaction: Some(Transaction::new(
+            TransactionKind::Deposit,
+            7,
+            "3.5".parse().unwrap(),
+        )),
+        counterpart: None,
+    };
+    let mut bytes = entry.encode();
+    assert_eq!(JournalEntry::decode(&bytes), Some(entry));
+
+    let mut other = ClientAccountState::new();
+    other.add("1.5".parse().unwrap()).unwrap();
+    let transfer = JournalEntry {
+        transaction: Some(Transaction::transfer(7, 8, "1.5".parse().unwrap())),
+        counterpart: Some((8, other)),
+        ..entry
+    };
+    assert_eq!(JournalEntry::decode(&transfer.encode()), Some(transfer));
+
+    let bare = JournalEntry {
+        account: None,
+        transaction: None,
+        ..entry
+    };
+    assert_eq!(JournalEntry::decode(&bare.encode()), Some(bare));
+
+    bytes[20] ^= 1;
+    assert_eq!(JournalEntry::decode(&bytes), None);
+}
+
+#[test]
+fn test_journal_replay_drops_torn_entry() {
+    use super::db::DB;
+
+    let path = super::temp_path("torn.journal");
+    let mut journal = Journal::open(&path, 1).unwrap();
+    journal.reset(0).unwrap();
+    let mut cas = ClientAccountState::new();
+    for offset in [10, 20] {
+        cas.add("1.0".parse().unwrap()).unwrap();
+        let entry = JournalEntry {
+            offset,
+            client: 1,
+            tx: offset as u32,
+            account: Some(cas),
+            transaction: None,
+            counterpart: None,
+        };
+        journal.append(&entry).unwrap();
+    }
+    drop(journal);
+
+    // A crash in the middle of the third entry.
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&[1u8; ENTRY_SIZE / 2]).unwrap();
+    drop(file);
+
+    let mut db = DB::new();
+    let mut journal = Journal::open(&path, 1).unwrap();
+    assert_eq!(journal.replay(&mut db, 0).unwrap(), Some(20));
+    assert_eq!(db.client(1).unwrap().total(), "2.0".parse().unwrap());
+    assert_eq!(
+        std::fs::metadata(&path).unwrap().len(),
+        HEADER_SIZE + 2 * ENTRY_SIZE as u64
+    );
+
+    drop(journal);
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,120 @@
+//! Credit limits. A client may withdraw past its available funds, as some
+//! ATMs allow, as long as they don't go below minus its credit limit.
+
+use super::amount::Amount;
+use super::columns::header_matches;
+use super::error::{EngineError, EngineErrorKind, Result};
+use csv::ByteRecord;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// Header of the client id column in a client configuration file.
+const CLIENT_HEADER: &str = "client";
+/// Header of the credit limit column in a client configuration file.
+const LIMIT_HEADER: &str = "credit_limit";
+
+/// How far below zero the available funds of each client may go.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CreditLimits {
+    /// Limit of the clients not listed, zero meaning no overdraft.
+    pub default: Amount,
+    /// Limits of given clients.
+    pub clients: BTreeMap<u16, Amount>,
+}
+
+impl CreditLimits {
+    /// The credit limit of `client`.
+    pub fn limit(&self, client: u16) -> Amount {
+        self.clients.get(&client).copied().unwrap_or(self.default)
+    }
+
+    /// Loads the per client limits of the client configuration file at
+    /// `path`, see [`CreditLimits::read`].
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let file = File::open(path)?;
+        self.read(file)
+    }
+
+    /// Reads per client limits from a client configuration csv. Its header
+    /// row names a `client` and a `credit_limit` column, the other ones
+    /// being ignored. A client with an empty limit keeps the default one.
+    /// Fails on the first row holding an invalid client id or a negative or
+    /// invalid limit, with its line.
+    pub fn read<R: io::Read>(&mut self, rdr: R) -> Result<()> {
+        let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(rdr);
+        let headers = rdr.byte_headers()?;
+        let find = |name| {
+            headers
+                .iter()
+                .position(|header| header_matches(header, name))
+        };
+        let columns = [find(CLIENT_HEADER), find(LIMIT_HEADER)];
+        let [Some(client), Some(limit)] = columns else {
+            let missing = [CLIENT_HEADER, LIMIT_HEADER]
+                .into_iter()
+                .zip(columns)
+                .filter(|(_, found)| found.is_none())
+                .map(|(name, _)| name.to_string())
+                .collect();
+            return Err(EngineError::new(EngineErrorKind::InvalidHeaders(missing)));
+        };
+
+        let mut row = ByteRecord::new();
+        while rdr.read_byte_record(&mut row)? {
+            let field = |i| row.get(i).map_or(&b""[..], <[u8]>::trim_ascii);
+            if field(limit).is_empty() {
+                continue;
+            }
+            let parsed = std::str::from_utf8(field(client))
+                .ok()
+                .and_then(|id| id.parse::<u16>().ok())
+                .zip(Amount::from_bytes(field(limit)).filter(|limit| !limit.is_negative()));
+            match parsed {
+                Some((client, limit)) => {
+                    self.clients.insert(client, limit);
+                }
+                None => {
+                    let line = row.position().map_or(0, |pos| pos.line());
+                    return Err(EngineError::new(EngineErrorKind::InvalidCreditLimit(line)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_credit_limits_read() {
+    let mut limits = CreditLimits {
+        default: "5.0".parse().unwrap(),
+        ..CreditLimits::default()
+    };
+    let csv = "name,Credit_Limit,client\n\
+               alice,100.5,1\n\
+               bob,,2\n\
+               carol,0,3\n";
+    limits.read(csv.as_bytes()).unwrap();
+    assert_eq!(limits.limit(1), "100.5".parse().unwrap());
+    assert_eq!(limits.limit(2), "5.0".parse().unwrap());
+    assert_eq!(limits.limit(3), Amount::ZERO);
+    assert_eq!(limits.limit(4), "5.0".parse().unwrap());
+
+    for (csv, line) in [
+        ("client,credit_limit\n1,10\n2,-1\n", 3),
+        ("client,credit_limit\nbob,10\n", 2),
+        ("client,credit_limit\n1,ten\n", 2),
+    ] {
+        let err = CreditLimits::default().read(csv.as_bytes()).unwrap_err();
+        assert!(matches!(err.kind(), EngineErrorKind::InvalidCreditLimit(l) if *l == line));
+    }
+
+    let err = CreditLimits::default()
+        .read("id,limit\n1,10\n".as_bytes())
+        .unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        EngineErrorKind::InvalidHeaders(missing) if missing == &["client", "credit_limit"]
+    ));
+}
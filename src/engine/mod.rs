@@ -1,229 +1,723 @@
 //! Transaction engine.
 //! Read a csv transaction file and act accordingly.
 
+mod amount;
+mod columns;
+mod compression;
+mod config;
 mod db;
 mod error;
+#[cfg(feature = "http")]
+mod http;
+mod inputs;
+mod journal;
+mod limits;
+mod output;
+mod parallel;
 mod protocol;
 mod record;
-use self::error::EngineErrorKind;
-use db::{client::ClientAccountState, DBError};
-use error::{EngineError, Result};
-use protocol::{Transaction, TransactionKind};
-use record::Record;
-
-#[allow(unused_imports)]
-use db::client::ClientDB;
-#[allow(unused_imports)]
-use db::TransactionDB;
+mod reject;
+mod server;
+mod snapshot;
+pub use amount::Amount;
+use columns::{names_columns, Rows};
+pub use columns::{Column, ColumnMap};
+pub use compression::{decompress, open_input};
+pub use config::{
+    ClientCreation, Config, DuplicatePolicy, FrozenPolicy, HeaderRow, WithdrawalDisputePolicy,
+};
+pub use db::{
+    client::ClientAccountState, DBError, DiskStorage, Storage, DB, DEFAULT_MEMORY_BUDGET,
+};
+pub use error::{EngineError, EngineErrorKind, Result};
+#[cfg(feature = "http")]
+pub use http::{serve_http, serve_http_shared};
+use inputs::Tally;
+pub use inputs::{expand_inputs, InputSummary};
+use journal::JournalEntry;
+pub use journal::{Journal, DEFAULT_SYNC_EVERY};
+pub use limits::CreditLimits;
+pub use output::{OutputFormat, OutputOptions, Quoting};
+pub use protocol::{Transaction, TransactionKind, TransactionState};
+pub use record::{Record, RecordError};
+pub use reject::{RejectFormat, RejectLog};
+pub use server::{serve, serve_shared};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
 
 /// The orchestrator of all this. Actually, the necessary metadatas
 /// are hard coded here. We migh move to to a configuration file later.
-pub struct Engine<'a> {
+pub struct Engine {
     // Client and Transaction database
-    db: db::DB,
-    record_headers: Vec<&'a str>,
-    output_header: &'a str,
+    db: Box<dyn Storage>,
+    // Business rules that are a matter of policy.
+    config: Config,
+    // Where we report the records we discard, if anywhere.
+    rejects: Option<RejectLog>,
+    // Where we write down the changes applied, if anywhere.
+    journal: Option<Journal>,
+    // Offset of the last row applied before a crash. Rows up to it are
+    // skipped from the next input.
+    resume_after: Option<u64>,
+    // Generation of the last snapshot loaded or saved, the journal being
+    // tied to it.
+    generation: u64,
+    // Rows read and rejected so far, for the input summaries.
+    tally: Tally,
+}
+
+/// What applying a record changes, staged until it's committed as a whole.
+#[derive(Debug)]
+struct Changes {
+    /// The client's account, as the record leaves it.
+    account: (u16, ClientAccountState),
+    /// The account on the other side of a transfer, as the record leaves
+    /// it.
+    counterpart: Option<(u16, ClientAccountState)>,
+    /// The transaction the record stores, or moves along the dispute
+    /// process.
+    transaction: TransactionChange,
+}
+
+#[derive(Debug)]
+enum TransactionChange {
+    /// A new deposit, withdrawal or transfer.
+    Insert(u32, Transaction),
+    /// A stored transaction reaching a new dispute state.
+    Update(u32, Transaction),
+}
+
+impl TransactionChange {
+    fn update(tx: u32, mut trx: Transaction, state: TransactionState) -> Self {
+        trx.set_state(state);
+        Self::Update(tx, trx)
+    }
 }
 
-impl<'a> Engine<'a> {
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    /// Returns an engine with empty databases and the default [`Config`].
     pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    /// Returns an engine with empty databases running with `config`.
+    pub fn with_config(config: Config) -> Self {
+        Self::with_storage(config, Box::new(DB::new()))
+    }
+
+    /// Returns an engine running with `config` on top of the `db` storage
+    /// backend.
+    pub fn with_storage(config: Config, db: Box<dyn Storage>) -> Self {
         Self {
-            db: db::DB::new(),
-            record_headers: vec!["type", "client", "tx", "amount"],
-            output_header: "client, available, held, total, locked",
+            db,
+            config,
+            rejects: None,
+            journal: None,
+            resume_after: None,
+            generation: 0,
+            tally: Tally::default(),
         }
     }
 
-    /// Read the csv file to process each transactions.
-    pub fn process(&mut self, path: &str) -> Result<()> {
-        let mut rdr = csv::Reader::from_path(path)?;
+    /// Reports every rejected record to `log` from now on.
+    pub fn set_reject_log(&mut self, log: RejectLog) {
+        self.rejects = Some(log);
+    }
+
+    /// Replays what `journal` holds on top of the current state, then writes
+    /// down every change applied from now on. The next input processed
+    /// resumes right after the last row the journal covers.
+    pub fn set_journal(&mut self, mut journal: Journal) -> Result<()> {
+        self.resume_after = journal.replay(self.db.as_mut(), self.generation)?;
+        self.journal = Some(journal);
+        Ok(())
+    }
+
+    /// Read the csv file to process each transactions. A compressed file is
+    /// decompressed on the fly, see [`open_input`].
+    pub fn process<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.process_files(&[path], 1).map(|_| ())
+    }
+
+    /// Read csv transactions from any [`io::Read`] source, like the standard
+    /// input or a decompression stream, and process each of them. With a
+    /// journal, the reader stands for an input without a path: a run it
+    /// left behind is only resumed by processing a reader again.
+    pub fn process_reader<R: io::Read>(&mut self, reader: R) -> Result<()> {
+        self.bind_journal::<&Path>(&[], None)?;
+        let mut rows = self.csv_reader(reader)?;
         let mut byte_record = csv::ByteRecord::new();
 
-        // Checks if we are fed the correct headers.
-        if rdr.byte_headers()? != self.record_headers {
-            return Err(EngineError::new(EngineErrorKind::InvalidHeaders));
-        }
-
-        while rdr.read_byte_record(&mut byte_record)? {
-            // If the parsing fail, we just simply discard this record.
-            if let Ok(record) = Record::from_byterecord(&mut byte_record) {
-                // Process the Record and update the DB accordingly.
-                // self.process_record(&record)?;
-                match self.process_record(&record) {
-                    Ok(()) => continue,
-                    // We should pop a log message here if we fail
-                    // to process a Record.
-                    Err(_) => (),
-                }
-            } else {
-                // We should probably pop a log message if a Record
-                // parsing fail here.
-                #[allow(clippy::unused_unit)]
-                ()
+        let mut offset = 0;
+        while rows.read(&mut byte_record)? {
+            offset += 1;
+            self.process_row(&mut byte_record, offset, &rows.columns)?;
+        }
+        self.finish_input()
+    }
+
+    /// Ties the journal, if any, to the inputs about to be processed, the
+    /// `paths` in this order, merged on a column or not. Fails if the run
+    /// resumes from a journal written for other inputs, as its rows would
+    /// be numbered differently.
+    fn bind_journal<P: AsRef<Path>>(&mut self, paths: &[P], merged_on: Option<&str>) -> Result<()> {
+        let Some(journal) = self.journal.as_mut() else {
+            return Ok(());
+        };
+        let inputs = journal::fingerprint(paths, merged_on);
+        if self.resume_after.is_some() && journal.inputs() != inputs {
+            return Err(EngineError::new(EngineErrorKind::InvalidJournal));
+        }
+        journal.set_inputs(inputs)
+    }
+
+    /// Applies a single csv row, `offset` numbering it across the inputs
+    /// for the journal. Rows the journal already covers are skipped.
+    fn process_row(
+        &mut self,
+        byte_record: &mut csv::ByteRecord,
+        offset: u64,
+        columns: &ColumnMap,
+    ) -> Result<()> {
+        // Already applied before the previous run went down.
+        if self.resume_after.is_some_and(|after| offset <= after) {
+            return Ok(());
+        }
+
+        // A rejected row is reported already, only a fatal error stops us.
+        match self.apply_row(byte_record, offset, columns) {
+            Err(err) if err.is_fatal() => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies a single csv row a partner sent, `columns` telling where its
+    /// columns are, and returns the record applied. A rejected row is
+    /// reported to the rejects log right away, its error being returned all
+    /// the same.
+    pub(crate) fn submit_row(
+        &mut self,
+        row: &mut csv::ByteRecord,
+        columns: &ColumnMap,
+    ) -> Result<Record> {
+        let outcome = self.apply_row(row, 0, columns);
+        if outcome.is_err() {
+            if let Some(log) = self.rejects.as_mut() {
+                log.flush()?;
+            }
+        }
+        outcome
+    }
+
+    /// Parses and applies a csv row, writing down what it changed to the
+    /// journal, if any. A rejected row is counted and reported to the log
+    /// on the way, unless the error means the run can't go on.
+    fn apply_row(
+        &mut self,
+        byte_record: &mut csv::ByteRecord,
+        offset: u64,
+        columns: &ColumnMap,
+    ) -> Result<Record> {
+        self.tally.rows += 1;
+
+        // Parsing trims the record in place, so we keep the raw row
+        // around when we need to report it.
+        let raw = self.rejects.as_ref().map(|_| byte_record.clone());
+
+        let outcome = match Record::from_columns(byte_record, columns) {
+            // Process the Record and update the DB accordingly.
+            Ok(record) => {
+                let before = self.journal_entry(&record, offset)?;
+                let outcome = self.process_record(&record);
+                self.write_journal(&record, offset, before)?;
+                outcome.map(|()| record)
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        if let Err(err) = &outcome {
+            if !err.is_fatal() {
+                self.report(raw, err)?;
             }
         }
+        outcome
+    }
+
+    /// Flushes what the input left pending once it's over.
+    fn finish_input(&mut self) -> Result<()> {
+        if let Some(log) = self.rejects.as_mut() {
+            log.flush()?;
+        }
+        if let Some(journal) = self.journal.as_mut() {
+            journal.sync()?;
+        }
+        self.resume_after = None;
 
         Ok(())
     }
 
-    /// Updates [`ClientDB`] and the [`TransactionDB`] databases. Currently only
-    /// the transaction one is updated only on deposit, dispute and resolve.
-    fn process_record(&mut self, record: &Record) -> Result<()> {
-        self.update_client_db(record)?;
-        self.update_transaction_db(record)?;
+    /// Returns the rows of the csv `reader`, along with where its header
+    /// row, if it has one, says the columns are.
+    fn csv_reader<R: io::Read>(&self, reader: R) -> Result<Rows<R>> {
+        // Rows with a missing or extra field are rejected one by one
+        // instead of aborting the whole file.
+        let mut rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_reader(reader);
+
+        let mut first = csv::ByteRecord::new();
+        let empty = !rdr.read_byte_record(&mut first)?;
+        let headerless = match self.config.header_row {
+            HeaderRow::Present => false,
+            HeaderRow::Absent => true,
+            // A first row that isn't a transaction only stands for a header
+            // row if it names a column, else it's rejected as a bad row.
+            HeaderRow::Detect => {
+                empty
+                    || Record::from_byterecord(&mut first.clone()).is_ok()
+                    || !names_columns(&first, &self.config.header_aliases)
+            }
+        };
+
+        if headerless {
+            Ok(Rows::without_headers(rdr, (!empty).then_some(first)))
+        } else {
+            let columns = ColumnMap::from_headers(&first, &self.config.header_aliases)?;
+            Ok(Rows::with_headers(rdr, first, columns))
+        }
+    }
+
+    /// Reports a rejected row to the log, if any. Fails if the error means
+    /// the run can't go on.
+    fn reject(&mut self, raw: Option<csv::ByteRecord>, err: EngineError) -> Result<()> {
+        if err.is_fatal() {
+            return Err(err);
+        }
+        self.report(raw, &err)
+    }
+
+    /// Counts a rejected row and reports it to the log, if any.
+    fn report(&mut self, raw: Option<csv::ByteRecord>, err: &EngineError) -> Result<()> {
+        self.tally.rejected += 1;
+        if let (Some(log), Some(raw)) = (self.rejects.as_mut(), raw) {
+            log.log(&raw, err)?;
+        }
         Ok(())
     }
 
-    /// Updates the [`TransactionDB`] database. Currently only
-    /// the deposit transaction kind is kept.
-    pub fn update_transaction_db(&mut self, record: &Record) -> Result<()> {
-        let trx_id = record.tx;
+    /// Returns the account and the transaction a record refers to, as a
+    /// journal entry, or None if there is no journal to write to.
+    fn journal_entry(&self, record: &Record, offset: u64) -> Result<Option<JournalEntry>> {
+        if self.journal.is_none() {
+            return Ok(None);
+        }
+        let transaction = self.db.transaction(record.tx)?;
+        // The other side of a transfer, or of the transfer being disputed.
+        let counterpart = record
+            .destination
+            .or_else(|| transaction.and_then(|trx| trx.destination()))
+            .and_then(|other| Some((other, self.db.client(other)?)));
+        Ok(Some(JournalEntry {
+            offset,
+            client: record.client,
+            tx: record.tx,
+            account: self.db.client(record.client),
+            transaction,
+            counterpart,
+        }))
+    }
 
-        // Only Deposit transactions are stored at the moment.
-        if record.transaction_kind == TransactionKind::Deposit {
-            match self.db.get_mut_transaction_db().get_mut(&trx_id) {
-                Some(_) => return Err((DBError::TransactionAlreadyExists).into()),
-                None => {
-                    let trx = Transaction::from_record(record);
-                    self.db.get_mut_transaction_db().insert(trx_id, trx);
+    /// Writes down what `record` changed, if anything, `before` being the
+    /// journal entry taken before applying it.
+    fn write_journal(
+        &mut self,
+        record: &Record,
+        offset: u64,
+        before: Option<JournalEntry>,
+    ) -> Result<()> {
+        if let Some(before) = before {
+            // Even a rejected record may have changed something.
+            if let Some(after) = self.journal_entry(record, offset)? {
+                if after != before {
+                    if let Some(journal) = self.journal.as_mut() {
+                        journal.append(&after)?;
+                    }
                 }
             }
         }
         Ok(())
     }
 
-    /// Updates the [`ClientDB`] database accordingly from a [`Record`].
-    pub fn update_client_db(&mut self, record: &Record) -> Result<()> {
+    /// Applies a single [`Record`], updating the client and the transaction
+    /// databases. The transaction one keeps deposits and withdrawals, and
+    /// tracks their dispute state. A rejected record is reported through
+    /// the returned error.
+    ///
+    /// A record is applied as a whole or not at all: its changes are staged
+    /// first and only written once they are all known to be valid.
+    pub fn process_record(&mut self, record: &Record) -> Result<()> {
+        if self.already_applied(record)? {
+            return Ok(());
+        }
+        let changes = self.stage(record)?;
+        self.commit(changes)
+    }
+
+    /// Checks the id of a deposit, withdrawal or transfer isn't taken yet, before
+    /// anything is changed. Returns true if the record repeats the stored
+    /// transaction exactly and the policy is to skip such rows.
+    fn already_applied(&self, record: &Record) -> Result<bool> {
+        if !matches!(
+            record.transaction_kind,
+            TransactionKind::Deposit | TransactionKind::Withdrawal | TransactionKind::Transfer
+        ) {
+            return Ok(false);
+        }
+        match self.db.transaction(record.tx)? {
+            None => Ok(false),
+            Some(trx)
+                if self.config.duplicates == DuplicatePolicy::Ignore
+                    && trx.kind() == record.transaction_kind
+                    && trx.client_id() == record.client
+                    && trx.destination() == record.destination
+                    && trx.amount() == record.amount =>
+            {
+                Ok(true)
+            }
+            Some(_) => Err(DBError::TransactionAlreadyExists.into()),
+        }
+    }
+
+    /// Works out what a [`Record`] changes to the client's account, to the
+    /// other side of a transfer and to the stored transactions, without
+    /// touching any of them.
+    fn stage(&self, record: &Record) -> Result<Changes> {
         let key = record.client;
+        let client = self.db.client(key);
 
-        match record.transaction_kind {
+        // A frozen account only accepts what the policy lets through.
+        if let Some(cas) = client {
+            self.check_frozen(&cas, record.transaction_kind)?;
+        }
+
+        // An unknown client only gets an account if the policy says so.
+        let creates = self.config.client_creation.creates(record.transaction_kind);
+        let account = || {
+            client
+                .or_else(|| creates.then(ClientAccountState::default))
+                .ok_or(DBError::ClientNotFound)
+        };
+
+        let mut counterpart = None;
+        let (cas, transaction) = match record.transaction_kind {
             TransactionKind::Deposit => {
-                // If the client doesn't exist in the DB, we create it.
-                self.db
-                    .get_mut_client_db()
-                    .entry(key)
-                    .or_insert_with(ClientAccountState::new);
-
-                if let Some(cas) = self.db.get_mut_client_db().get_mut(&key) {
-                    cas.add(record.amount)?;
-                }
+                let mut cas = account()?;
+                cas.add(record.amount)?;
+                let trx = Transaction::from_record(record);
+                (cas, TransactionChange::Insert(record.tx, trx))
             }
             TransactionKind::Withdrawal => {
-                if let Some(cas) = self.db.get_mut_client_db().get_mut(&key) {
-                    if cas.available() >= record.amount {
-                        cas.sub(record.amount)?;
-                    } else {
-                        return Err(DBError::NotEnoughAvailableCredit.into());
+                let mut cas = account()?;
+                self.debit(&mut cas, key, record.amount)?;
+                let trx = Transaction::from_record(record);
+                (cas, TransactionChange::Insert(record.tx, trx))
+            }
+            TransactionKind::Transfer => {
+                let destination = record
+                    .destination
+                    .filter(|destination| *destination != key)
+                    .ok_or(RecordError::Invalid)?;
+                let mut cas = account()?;
+                self.debit(&mut cas, key, record.amount)?;
+                // The receiving side is credited the way a deposit is.
+                let mut other = match self.db.client(destination) {
+                    Some(other) => {
+                        self.check_frozen(&other, TransactionKind::Deposit)?;
+                        other
                     }
-                }
+                    None if self
+                        .config
+                        .client_creation
+                        .creates(TransactionKind::Deposit) =>
+                    {
+                        ClientAccountState::default()
+                    }
+                    None => return Err(DBError::ClientNotFound.into()),
+                };
+                other.add(record.amount)?;
+                counterpart = Some((destination, other));
+                let trx = Transaction::from_record(record);
+                (cas, TransactionChange::Insert(record.tx, trx))
             }
             TransactionKind::Dispute => {
-                // Lock the account until conflict resolution.
-                if let Some(trx) = self.db.get_mut_transaction_db().get_mut(&record.tx) {
-                    // Set a transaction as in dispute.
-                    trx.set_dispute(true);
-
-                    let amount = trx.amount();
-
-                    // We should check that a dispute transaction's client_id refer
-                    // to the same client_id from the original transaction
-                    if trx.client_id() != record.client {
-                        return Err(DBError::ClientIdMismatch.into());
-                    }
-
-                    if let Some(cas) = self.db.get_mut_client_db().get_mut(&key) {
-                        cas.hold(amount);
-                    } else {
-                        // This Dispute transaction refer to an unknown Client,
-                        // so we discard it and we return an error.
-                        return Err(DBError::ClientNotFound.into());
+                // Holds the disputed amount until conflict resolution.
+                let (trx, next) = self.dispute_transition(record)?;
+                let mut cas = account()?;
+                match trx.kind() {
+                    TransactionKind::Deposit => cas.hold(trx.amount())?,
+                    // The client claims the withdrawn funds back.
+                    TransactionKind::Withdrawal => cas.credit_held(trx.amount())?,
+                    // The funds are held where they went.
+                    TransactionKind::Transfer => {
+                        let (destination, mut other) = self.counterpart(record, &trx)?;
+                        other.hold(trx.amount())?;
+                        counterpart = Some((destination, other));
                     }
-                } else {
-                    return Err(DBError::TransactionNotFound.into());
+                    _ => return Err(DBError::OperationNotPermitted.into()),
                 }
+                (cas, TransactionChange::update(record.tx, trx, next))
             }
             TransactionKind::Resolve => {
                 // Resolves a disputed transaction and release the held funds.
-                if let Some(trx) = self.db.get_mut_transaction_db().get_mut(&record.tx) {
-                    // This transaction is no longer in dispute.
-                    trx.set_dispute(false);
-
-                    let amount = trx.amount();
-
-                    if let Some(cas) = self.db.get_mut_client_db().get_mut(&key) {
-                        cas.unhold(amount)?;
-                    } else {
-                        return Err(DBError::ClientNotFound.into());
+                let (trx, next) = self.dispute_transition(record)?;
+                let mut cas = account()?;
+                match trx.kind() {
+                    TransactionKind::Deposit => cas.unhold(trx.amount())?,
+                    // The withdrawal stands, the claimed funds are dropped.
+                    TransactionKind::Withdrawal => cas.debit_held(trx.amount())?,
+                    // The transfer stands, its receiver gets the funds back.
+                    TransactionKind::Transfer => {
+                        let (destination, mut other) = self.counterpart(record, &trx)?;
+                        other.unhold(trx.amount())?;
+                        counterpart = Some((destination, other));
                     }
-                } else {
-                    return Err(DBError::TransactionNotFound.into());
+                    _ => return Err(DBError::OperationNotPermitted.into()),
                 }
+                (cas, TransactionChange::update(record.tx, trx, next))
             }
             TransactionKind::Chargeback => {
-                if let Some(trx) = self.db.get_mut_transaction_db().get_mut(&record.tx) {
-                    let amount = trx.amount();
-
-                    if trx.is_in_dispute() {
-                        if let Some(cas) = self.db.get_mut_client_db().get_mut(&key) {
-                            cas.unhold(amount)?;
-                            cas.sub(amount)?;
-                            cas.lock();
-                        } else {
-                            return Err(DBError::ClientNotFound.into());
-                        }
-                    } else {
-                        return Err(DBError::TransactionNotInDispute.into());
+                let (trx, next) = self.dispute_transition(record)?;
+                let mut cas = account()?;
+                match trx.kind() {
+                    TransactionKind::Deposit => {
+                        cas.unhold(trx.amount())?;
+                        cas.sub(trx.amount())?;
                     }
+                    // The withdrawal is reversed, the client gets the funds
+                    // back.
+                    TransactionKind::Withdrawal => cas.unhold(trx.amount())?,
+                    // Both sides of the transfer are reversed.
+                    TransactionKind::Transfer => {
+                        let (destination, mut other) = self.counterpart(record, &trx)?;
+                        other.unhold(trx.amount())?;
+                        other.sub(trx.amount())?;
+                        cas.add(trx.amount())?;
+                        counterpart = Some((destination, other));
+                    }
+                    _ => return Err(DBError::OperationNotPermitted.into()),
                 }
+                cas.lock();
+                (cas, TransactionChange::update(record.tx, trx, next))
             }
             #[allow(unreachable_patterns)]
             _ => return Err(EngineError::new(EngineErrorKind::UnknownTransaction)),
+        };
+
+        Ok(Changes {
+            account: (key, cas),
+            counterpart,
+            transaction,
+        })
+    }
+
+    /// Writes staged changes down. The transaction store, the only one that
+    /// may fail, goes first: if it does, nothing was changed.
+    fn commit(&mut self, changes: Changes) -> Result<()> {
+        match changes.transaction {
+            TransactionChange::Insert(tx, trx) => self.db.insert_transaction(tx, trx)?,
+            TransactionChange::Update(tx, trx) => self.db.update_transaction(tx, trx)?,
         }
+        let (client, cas) = changes.account;
+        self.db.set_client(client, cas);
+        if let Some((client, cas)) = changes.counterpart {
+            self.db.set_client(client, cas);
+        }
+        Ok(())
+    }
 
+    /// Fails if `cas` is frozen and the policy doesn't let a transaction of
+    /// `kind` through.
+    fn check_frozen(&self, cas: &ClientAccountState, kind: TransactionKind) -> Result<()> {
+        if cas.locked() && !self.config.frozen_policy.allows(kind) {
+            return Err(DBError::AccountLocked.into());
+        }
         Ok(())
     }
 
-    /// Print the state of the client's account database.
-    pub fn print_db(&self) {
-        println!("{}", self.output_header);
-        for (key, value) in self.db.get_client_db().iter() {
-            let s = format!(
-                "{:>6}, {:>9.4}, {:>4.4}, {:>5.4}, {:>6}",
-                key,
-                value.available(),
-                value.held(),
-                value.total(),
-                value.locked()
-            );
-            println!("{}", s);
+    /// Takes `amount` out of the account of `client`, whose available funds
+    /// may go down to minus its credit limit.
+    fn debit(&self, cas: &mut ClientAccountState, client: u16, amount: Amount) -> Result<()> {
+        // An overflow meaning there's plenty.
+        let funds = cas
+            .available()
+            .checked_add(self.config.credit_limits.limit(client));
+        if funds.is_some_and(|funds| funds < amount) {
+            return Err(DBError::NotEnoughAvailableCredit.into());
+        }
+        cas.sub(amount)?;
+        Ok(())
+    }
+
+    /// The receiving account of a disputed transfer, which the frozen
+    /// policy applies to as well.
+    fn counterpart(&self, record: &Record, trx: &Transaction) -> Result<(u16, ClientAccountState)> {
+        let destination = trx.destination().ok_or(DBError::OperationNotPermitted)?;
+        let other = self.db.client(destination).ok_or(DBError::ClientNotFound)?;
+        self.check_frozen(&other, record.transaction_kind)?;
+        Ok((destination, other))
+    }
+
+    /// Looks up the transaction a dispute, resolve or chargeback refers to
+    /// and checks the record is allowed to move it forward in the dispute
+    /// process. Returns the disputed transaction along with the state it
+    /// reaches, or fails with [`DBError::TransactionNotFound`] if it's
+    /// unknown.
+    fn dispute_transition(&self, record: &Record) -> Result<(Transaction, TransactionState)> {
+        let trx = self
+            .db
+            .transaction(record.tx)?
+            .ok_or(DBError::TransactionNotFound)?;
+        // We should check that a dispute transaction's client_id refer
+        // to the same client_id from the original transaction
+        if trx.client_id() != record.client {
+            return Err(DBError::ClientIdMismatch.into());
+        }
+        // A transfer is disputed by its sender, whatever the policy about
+        // withdrawals.
+        if trx.kind() == TransactionKind::Withdrawal
+            && self.config.withdrawal_disputes == WithdrawalDisputePolicy::Refuse
+        {
+            return Err(DBError::OperationNotPermitted.into());
+        }
+        let next = trx.state().next(record.transaction_kind)?;
+        Ok((trx, next))
+    }
+
+    /// Returns the state of a client's account, or None if the client is
+    /// unknown.
+    pub fn account(&self, client: u16) -> Option<ClientAccountState> {
+        self.db.client(client)
+    }
+
+    /// Iterates over every client's account, ordered by client id.
+    pub fn accounts(&self) -> impl Iterator<Item = (u16, ClientAccountState)> + '_ {
+        self.db.clients()
+    }
+
+    /// Returns a stored transaction, deposit or withdrawal, along with its
+    /// dispute state, or None if it's unknown. Fails if the storage backend
+    /// can't reach it.
+    pub fn transaction(&self, tx: u32) -> Result<Option<Transaction>> {
+        Ok(self.db.transaction(tx)?)
+    }
+
+    /// Writes the state of the client's account database to `wtr`, as csv
+    /// unless the legacy layout is asked for.
+    pub fn write_db<W: io::Write>(&self, wtr: W, options: &OutputOptions) -> Result<()> {
+        output::write_report(self.db.clients(), wtr, options)
+    }
+
+    /// Saves a snapshot of the databases to `path`, dispute states and
+    /// frozen accounts included. The file is replaced at once, a failure
+    /// leaving the previous snapshot untouched. The journal, if any, is
+    /// emptied as the snapshot now covers it. The snapshot being a new
+    /// generation, a crash before the journal is emptied leaves a journal
+    /// the next run knows to drop.
+    pub fn save_state<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let generation = self.generation + 1;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let file = File::create(&tmp)?;
+        snapshot::write(self.db.as_ref(), generation, &file)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        // The rename only lasts once the directory entry is on disk too.
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        self.generation = generation;
+
+        if let Some(journal) = self.journal.as_mut() {
+            journal.reset(generation)?;
         }
+        Ok(())
+    }
+
+    /// Writes a snapshot of the databases to `wtr`.
+    pub fn write_state<W: io::Write>(&self, wtr: W) -> Result<()> {
+        snapshot::write(self.db.as_ref(), self.generation, wtr)
+    }
+
+    /// Loads the snapshot saved at `path` by [`Engine::save_state`], so the
+    /// records processed from now on apply on top of it. Meant for an engine
+    /// that didn't process anything yet.
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let file = File::open(path)?;
+        self.read_state(file)
+    }
+
+    /// Loads a snapshot written by [`Engine::write_state`] from `rdr`.
+    pub fn read_state<R: io::Read>(&mut self, rdr: R) -> Result<()> {
+        self.generation = snapshot::read(rdr, self.db.as_mut())?;
+        Ok(())
     }
 }
 
+/// A path in the temp directory for the test files named `name`, apart from
+/// those of other test runs.
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("k-coding-test-{}-{}", std::process::id(), name))
+}
+
 #[cfg(test)]
 mod test_engine {
     use crate::engine::protocol;
 
     use super::*;
+    use crate::engine::amount::Amount;
     use crate::engine::db::client;
+    use crate::engine::reject::RejectFormat;
 
-    fn mock_engine<'a>() -> Engine<'a> {
-        let mut engine = Engine::new();
+    fn amount(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    fn mock_engine() -> Engine {
+        mock_engine_with(Config::default())
+    }
+
+    fn mock_engine_with(config: Config) -> Engine {
+        let mut engine = Engine::with_config(config);
 
         let mut cas1 = client::ClientAccountState::new();
         let mut cas2 = client::ClientAccountState::new();
-        cas1.add(10.0).unwrap();
-        cas2.add(20.0).unwrap();
+        cas1.add(amount("10.0")).unwrap();
+        cas2.add(amount("20.0")).unwrap();
 
         let deposit_tk = protocol::TransactionKind::Deposit;
-        let trx1 = protocol::Transaction::new(deposit_tk, 1, 10.0);
-        let trx2 = protocol::Transaction::new(deposit_tk, 2, 20.0);
+        let trx1 = protocol::Transaction::new(deposit_tk, 1, amount("10.0"));
+        let trx2 = protocol::Transaction::new(deposit_tk, 2, amount("20.0"));
 
-        engine.db.get_mut_client_db().insert(1, cas1);
-        engine.db.get_mut_client_db().insert(2, cas2);
+        engine.db.set_client(1, cas1);
+        engine.db.set_client(2, cas2);
 
-        engine.db.get_mut_transaction_db().insert(1, trx1);
-        engine.db.get_mut_transaction_db().insert(2, trx2);
+        engine.db.insert_transaction(1, trx1).unwrap();
+        engine.db.insert_transaction(2, trx2).unwrap();
 
         engine
     }
@@ -236,15 +730,16 @@ mod test_engine {
             transaction_kind: TransactionKind::Deposit,
             client: 1,
             tx: 3,
-            amount: 10.0,
+            amount: amount("10.0"),
+            destination: None,
         };
 
         engine.process_record(&record).unwrap();
-        assert_eq!(engine.db.get_client_db().get(&1).unwrap().total(), 20.0);
+        assert_eq!(engine.account(1).unwrap().total(), amount("20.0"));
         // Checks if we keep tracks of the transaction because it's a deposit.
         assert_eq!(
-            engine.db.get_transaction_db().get(&3).unwrap().amount(),
-            10.0
+            engine.db.transaction(3).unwrap().unwrap().amount(),
+            amount("10.0")
         );
     }
 
@@ -256,11 +751,12 @@ mod test_engine {
             transaction_kind: TransactionKind::Withdrawal,
             client: 1,
             tx: 3,
-            amount: 3.0,
+            amount: amount("3.0"),
+            destination: None,
         };
 
         engine.process_record(&record).unwrap();
-        assert_eq!(engine.db.get_client_db().get(&1).unwrap().total(), 7.0);
+        assert_eq!(engine.account(1).unwrap().total(), amount("7.0"));
     }
 
     #[test]
@@ -271,7 +767,8 @@ mod test_engine {
             transaction_kind: TransactionKind::Deposit,
             client: 1,
             tx: 3,
-            amount: 3.0,
+            amount: amount("3.0"),
+            destination: None,
         };
         engine.process_record(&record_deposit).unwrap();
 
@@ -279,17 +776,18 @@ mod test_engine {
             transaction_kind: TransactionKind::Dispute,
             client: 1,
             tx: 3,
-            amount: 0.0,
+            amount: amount("0.0"),
+            destination: None,
         };
         engine.process_record(&record_dispute).unwrap();
 
-        let cas = engine.db.get_client_db().get(&1).unwrap();
-        assert_eq!(cas.total(), 13.0);
-        assert_eq!(cas.available(), 10.0);
-        assert_eq!(cas.held(), 3.0);
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.total(), amount("13.0"));
+        assert_eq!(cas.available(), amount("10.0"));
+        assert_eq!(cas.held(), amount("3.0"));
 
-        let tx = engine.db.get_transaction_db().get(&3).unwrap();
-        assert_eq!(tx.is_in_dispute(), true);
+        let tx = engine.db.transaction(3).unwrap().unwrap();
+        assert!(tx.is_in_dispute());
     }
 
     #[test]
@@ -301,19 +799,22 @@ mod test_engine {
                 transaction_kind: TransactionKind::Deposit,
                 client: 1,
                 tx: 3,
-                amount: 3.0,
+                amount: amount("3.0"),
+                destination: None,
             },
             Record {
                 transaction_kind: TransactionKind::Dispute,
                 client: 1,
                 tx: 3,
-                amount: 0.0,
+                amount: amount("0.0"),
+                destination: None,
             },
             Record {
                 transaction_kind: TransactionKind::Resolve,
                 client: 1,
                 tx: 3,
-                amount: 0.0,
+                amount: amount("0.0"),
+                destination: None,
             },
         ];
 
@@ -321,14 +822,14 @@ mod test_engine {
             engine.process_record(&record).unwrap();
         }
 
-        let cas = engine.db.get_client_db().get(&1).unwrap();
+        let cas = engine.account(1).unwrap();
 
-        assert_eq!(cas.held(), 0.0);
-        assert_eq!(cas.available(), 13.0);
-        assert_eq!(cas.total(), 13.0);
+        assert_eq!(cas.held(), amount("0.0"));
+        assert_eq!(cas.available(), amount("13.0"));
+        assert_eq!(cas.total(), amount("13.0"));
 
-        let tx = engine.db.get_transaction_db().get(&3).unwrap();
-        assert_eq!(tx.is_in_dispute(), false);
+        let tx = engine.db.transaction(3).unwrap().unwrap();
+        assert!(!tx.is_in_dispute());
     }
 
     #[test]
@@ -340,19 +841,22 @@ mod test_engine {
                 transaction_kind: TransactionKind::Deposit,
                 client: 1,
                 tx: 3,
-                amount: 3.0,
+                amount: amount("3.0"),
+                destination: None,
             },
             Record {
                 transaction_kind: TransactionKind::Dispute,
                 client: 1,
                 tx: 3,
-                amount: 0.0,
+                amount: amount("0.0"),
+                destination: None,
             },
             Record {
                 transaction_kind: TransactionKind::Chargeback,
                 client: 1,
                 tx: 3,
-                amount: 0.0,
+                amount: amount("0.0"),
+                destination: None,
             },
         ];
 
@@ -360,14 +864,1260 @@ mod test_engine {
             engine.process_record(&record).unwrap();
         }
 
-        let cas = engine.db.get_client_db().get(&1).unwrap();
+        let cas = engine.account(1).unwrap();
+
+        assert!(cas.locked());
+        assert_eq!(cas.held(), amount("0.0"));
+        assert_eq!(cas.available(), amount("10.0"));
+        assert_eq!(cas.total(), amount("10.0"));
+
+        let tx = engine.db.transaction(3).unwrap().unwrap();
+        assert_eq!(tx.state(), TransactionState::ChargedBack);
+    }
+
+    #[test]
+    fn test_storage_failure_aborts() {
+        /// Storage whose transaction index is unreachable.
+        struct Unreachable(DB);
+
+        impl Storage for Unreachable {
+            fn client(&self, client: u16) -> Option<ClientAccountState> {
+                self.0.client(client)
+            }
+            fn set_client(&mut self, client: u16, cas: ClientAccountState) {
+                self.0.set_client(client, cas)
+            }
+            fn clients(&self) -> Box<dyn Iterator<Item = (u16, ClientAccountState)> + '_> {
+                self.0.clients()
+            }
+            fn transactions(
+                &self,
+            ) -> Box<dyn Iterator<Item = db::Result<(u32, Transaction)>> + '_> {
+                Box::new(std::iter::once(Err(DBError::StorageFailure(
+                    io::ErrorKind::Other,
+                ))))
+            }
+            fn transaction(&self, _tx: u32) -> db::Result<Option<Transaction>> {
+                Err(DBError::StorageFailure(io::ErrorKind::Other))
+            }
+            fn insert_transaction(&mut self, _tx: u32, _trx: Transaction) -> db::Result<()> {
+                Err(DBError::StorageFailure(io::ErrorKind::Other))
+            }
+            fn update_transaction(&mut self, _tx: u32, _trx: Transaction) -> db::Result<()> {
+                Err(DBError::StorageFailure(io::ErrorKind::Other))
+            }
+        }
+
+        let mut engine = Engine::with_storage(Config::default(), Box::new(Unreachable(DB::new())));
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.5\n\
+                     deposit,1,2,2.5\n";
+
+        let err = engine.process_reader(input.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            EngineErrorKind::DBError(DBError::StorageFailure(io::ErrorKind::Other))
+        ));
+        // The run stopped on the first deposit, before crediting it.
+        assert!(engine.account(1).is_none());
+    }
+
+    /// Storage whose transaction store fails on the `n`th call from when
+    /// it's armed, `usize::MAX` meaning it's not.
+    struct Flaky {
+        db: DB,
+        countdown: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Flaky {
+        fn trip(&self) -> db::Result<()> {
+            use std::sync::atomic::Ordering::SeqCst;
+            match self.countdown.load(SeqCst) {
+                usize::MAX => Ok(()),
+                0 => {
+                    self.countdown.store(usize::MAX, SeqCst);
+                    Err(DBError::StorageFailure(io::ErrorKind::Other))
+                }
+                n => {
+                    self.countdown.store(n - 1, SeqCst);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    impl Storage for Flaky {
+        fn client(&self, client: u16) -> Option<ClientAccountState> {
+            self.db.client(client)
+        }
+        fn set_client(&mut self, client: u16, cas: ClientAccountState) {
+            self.db.set_client(client, cas)
+        }
+        fn clients(&self) -> Box<dyn Iterator<Item = (u16, ClientAccountState)> + '_> {
+            self.db.clients()
+        }
+        fn transactions(&self) -> Box<dyn Iterator<Item = db::Result<(u32, Transaction)>> + '_> {
+            self.db.transactions()
+        }
+        fn transaction(&self, tx: u32) -> db::Result<Option<Transaction>> {
+            self.trip()?;
+            self.db.transaction(tx)
+        }
+        fn insert_transaction(&mut self, tx: u32, trx: Transaction) -> db::Result<()> {
+            self.trip()?;
+            self.db.insert_transaction(tx, trx)
+        }
+        fn update_transaction(&mut self, tx: u32, trx: Transaction) -> db::Result<()> {
+            self.trip()?;
+            self.db.update_transaction(tx, trx)
+        }
+    }
+
+    /// Every account and stored transaction of an engine.
+    type State = (Vec<(u16, ClientAccountState)>, Vec<(u32, Transaction)>);
+
+    fn state_of(engine: &Engine) -> State {
+        let transactions = engine.db.transactions().map(|trx| trx.unwrap()).collect();
+        (engine.db.clients().collect(), transactions)
+    }
+
+    #[test]
+    fn test_record_applied_as_a_whole() {
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+        use TransactionKind::*;
+
+        let credit = Config {
+            withdrawal_disputes: WithdrawalDisputePolicy::Credit,
+            ..Config::default()
+        };
+        // Records applied beforehand, then the one failures are injected in.
+        let scenarios = [
+            (Config::default(), vec![], record(Deposit, 3, amount("5.0"))),
+            (
+                Config::default(),
+                vec![],
+                record(Withdrawal, 3, amount("4.0")),
+            ),
+            (Config::default(), vec![], record(Dispute, 1, Amount::ZERO)),
+            (
+                Config::default(),
+                vec![record(Dispute, 1, Amount::ZERO)],
+                record(Resolve, 1, Amount::ZERO),
+            ),
+            (
+                Config::default(),
+                vec![record(Dispute, 1, Amount::ZERO)],
+                record(Chargeback, 1, Amount::ZERO),
+            ),
+            (
+                credit.clone(),
+                vec![record(Withdrawal, 3, amount("4.0"))],
+                record(Dispute, 3, Amount::ZERO),
+            ),
+            (
+                credit,
+                vec![
+                    record(Withdrawal, 3, amount("4.0")),
+                    record(Dispute, 3, Amount::ZERO),
+                ],
+                record(Chargeback, 3, Amount::ZERO),
+            ),
+            (
+                Config::default(),
+                vec![],
+                Record::transfer(1, 3, 9, amount("4.0")),
+            ),
+            (
+                Config::default(),
+                vec![
+                    Record::transfer(1, 3, 9, amount("4.0")),
+                    record(Dispute, 3, Amount::ZERO),
+                ],
+                record(Chargeback, 3, Amount::ZERO),
+            ),
+        ];
+
+        for (config, setup, last) in scenarios {
+            let countdown = std::sync::Arc::new(AtomicUsize::new(usize::MAX));
+            let flaky = Flaky {
+                db: DB::new(),
+                countdown: countdown.clone(),
+            };
+            let mut engine = Engine::with_storage(config.clone(), Box::new(flaky));
+            let mut expected = mock_engine_with(config);
+            engine.db.set_client(1, expected.account(1).unwrap());
+            engine
+                .db
+                .insert_transaction(1, expected.db.transaction(1).unwrap().unwrap())
+                .unwrap();
+            for rec in setup.iter() {
+                engine.process_record(rec).unwrap();
+                expected.process_record(rec).unwrap();
+            }
+            expected.process_record(&last).unwrap();
+
+            // Fails each storage call in turn, until none is left to fail.
+            for n in 0.. {
+                let before = state_of(&engine);
+                countdown.store(n, SeqCst);
+                let outcome = engine.process_record(&last);
+                let tripped = countdown.load(SeqCst) == usize::MAX;
+                countdown.store(usize::MAX, SeqCst);
+
+                if tripped {
+                    assert!(outcome.is_err(), "{:?} ignored failure {}", last, n);
+                    assert_eq!(state_of(&engine), before, "{:?} failing at {}", last, n);
+                } else {
+                    outcome.unwrap();
+                    assert_eq!(engine.account(1), expected.account(1));
+                    assert_eq!(engine.account(9), expected.account(9));
+                    assert_eq!(
+                        engine.db.transaction(last.tx).unwrap(),
+                        expected.db.transaction(last.tx).unwrap()
+                    );
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_account_failure_stores_nothing() {
+        let mut engine = mock_engine();
+        let before = state_of(&engine);
+
+        // Crediting client 1 overflows its balance.
+        let huge = Amount::from_raw(i64::MAX);
+        let result = engine.process_record(&record(TransactionKind::Deposit, 3, huge));
+        assert_eq!(db_error(result), DBError::AmountOverflow);
+        assert_eq!(state_of(&engine), before);
+        assert!(engine.db.transaction(3).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_resume_from_state() {
+        let mut engine = Engine::new();
+        let day1 = "type,client,tx,amount\n\
+                    deposit,1,1,10.0\n\
+                    deposit,1,2,5.0\n\
+                    dispute,1,1,\n\
+                    deposit,2,3,1.0\n\
+                    dispute,2,3,\n\
+                    chargeback,2,3,\n";
+        engine.process_reader(day1.as_bytes()).unwrap();
+        let mut state = Vec::new();
+        engine.write_state(&mut state).unwrap();
+
+        let mut engine = Engine::new();
+        engine.read_state(state.as_slice()).unwrap();
+        let day2 = "type,client,tx,amount\n\
+                    resolve,1,1,\n\
+                    deposit,2,4,1.0\n\
+                    dispute,1,2,\n";
+        engine.process_reader(day2.as_bytes()).unwrap();
+
+        assert_balances(&engine, "10.0", "5.0", "15.0");
+        assert_eq!(tx_state(&engine, 1), TransactionState::Resolved);
+        assert_eq!(tx_state(&engine, 2), TransactionState::Disputed);
+        assert!(engine.account(2).unwrap().locked());
+        assert_eq!(engine.account(2).unwrap().total(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_recover_from_journal() {
+        let path = temp_path("recover.journal");
+        let input = "type,client,tx,amount,destination\n\
+                     deposit,1,1,10.0,\n\
+                     deposit,1,2,5.0,\n\
+                     withdrawal,1,3,20.0,\n\
+                     dispute,1,1,,\n\
+                     transfer,1,5,2.0,3\n\
+                     deposit,2,4,1.0,\n\
+                     resolve,1,1,,\n";
+        // The run goes down right after the transfer.
+        let crash = input.find("deposit,2").unwrap();
+
+        let mut engine = Engine::new();
+        engine
+            .set_journal(Journal::open(&path, 1).unwrap())
+            .unwrap();
+        engine.process_reader(&input.as_bytes()[..crash]).unwrap();
+        drop(engine);
+
+        let mut engine = Engine::new();
+        engine
+            .set_journal(Journal::open(&path, 1).unwrap())
+            .unwrap();
+        assert_balances(&engine, "3.0", "10.0", "13.0");
+        assert_eq!(engine.account(3).unwrap().total(), amount("2.0"));
+        engine.process_reader(input.as_bytes()).unwrap();
+
+        let mut expected = Engine::new();
+        expected.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(
+            engine.accounts().collect::<Vec<_>>(),
+            expected.accounts().collect::<Vec<_>>()
+        );
+        assert_eq!(tx_state(&engine, 1), TransactionState::Resolved);
+
+        drop(engine);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_journal_older_than_snapshot() {
+        let journal_path = temp_path("generation.journal");
+        let state_path = temp_path("generation.state");
+        let day1 = "type,client,tx,amount\n\
+                    deposit,1,1,10.0\n\
+                    deposit,1,2,5.0\n";
+        let day2 = "type,client,tx,amount\n\
+                    deposit,1,3,1.0\n";
+
+        let mut engine = Engine::new();
+        engine
+            .set_journal(Journal::open(&journal_path, 1).unwrap())
+            .unwrap();
+        engine.process_reader(day1.as_bytes()).unwrap();
+        // The run goes down once the snapshot is saved, before the journal
+        // is emptied.
+        let journal = engine.journal.take();
+        engine.save_state(&state_path).unwrap();
+        drop(journal);
+
+        let mut engine = Engine::new();
+        engine.load_state(&state_path).unwrap();
+        engine
+            .set_journal(Journal::open(&journal_path, 1).unwrap())
+            .unwrap();
+        assert_eq!(engine.resume_after, None);
+        engine.process_reader(day2.as_bytes()).unwrap();
+        assert_balances(&engine, "16.0", "0.0", "16.0");
+        drop(engine);
+
+        // A journal newer than the snapshot can't be trusted on top of it.
+        let mut engine = Engine::new();
+        let err = engine
+            .set_journal(Journal::open(&journal_path, 1).unwrap())
+            .unwrap_err();
+        assert!(matches!(err.kind(), EngineErrorKind::InvalidJournal));
+
+        std::fs::remove_file(journal_path).unwrap();
+        std::fs::remove_file(state_path).unwrap();
+    }
+
+    #[test]
+    fn test_with_storage() {
+        let mut db = DB::new();
+        let mut cas = ClientAccountState::new();
+        cas.add(amount("5.0")).unwrap();
+        db.set_client(1, cas);
+        db.insert_transaction(
+            1,
+            Transaction::new(TransactionKind::Deposit, 1, amount("5.0")),
+        )
+        .unwrap();
+
+        let mut engine = Engine::with_storage(Config::default(), Box::new(db));
+        engine
+            .process_record(&record(TransactionKind::Dispute, 1, Amount::ZERO))
+            .unwrap();
+
+        assert_balances(&engine, "0.0", "5.0", "5.0");
+        assert_eq!(tx_state(&engine, 1), TransactionState::Disputed);
+    }
+
+    #[test]
+    fn test_process_reader() {
+        let mut engine = Engine::new();
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.5\n\
+                     withdrawal,1,2,1.0\n\
+                     deposit,2,3,4.0\n";
+
+        engine.process_reader(input.as_bytes()).unwrap();
+
+        assert_eq!(engine.account(1).unwrap().total(), amount("1.5"));
+        assert_eq!(engine.account(2).unwrap().total(), amount("4.0"));
+    }
+
+    #[test]
+    fn test_process_reader_invalid_headers() {
+        let mut engine = Engine::new();
+        let input = "kind,client,tx,amount\ndeposit,1,1,2.5\n";
+
+        let err = engine.process_reader(input.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid headers encountered, missing: type"
+        );
+        assert!(engine.accounts().next().is_none());
+    }
+
+    #[test]
+    fn test_process_reader_headerless() {
+        let input = "deposit,1,1,2.5\nwithdrawal,1,2,1.0\n";
+
+        let mut engine = Engine::new();
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), amount("1.5"));
+
+        let mut engine = Engine::with_config(Config {
+            header_row: HeaderRow::Absent,
+            ..Config::default()
+        });
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), amount("1.5"));
+
+        // A malformed first row naming no column is a bad row, not a
+        // header row.
+        let mut engine = Engine::new();
+        let input = "deposit,x,1,2.5\ndeposit,1,2,1.0\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), amount("1.0"));
+        assert_eq!(engine.tally.rejected, 1);
+
+        // Unknown names only don't make a header row either, the row is
+        // rejected as data instead of failing the input.
+        let mut engine = Engine::new();
+        let input = "kind,who,id,value\ndeposit,1,2,1.0\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), amount("1.0"));
+        assert_eq!(engine.tally.rejected, 1);
+
+        // Told to expect a header row, the first row is taken for one.
+        let mut engine = Engine::with_config(Config {
+            header_row: HeaderRow::Present,
+            ..Config::default()
+        });
+        assert!(engine.process_reader(input.as_bytes()).is_err());
+
+        // Told there is none, the header row is rejected as a transaction.
+        let mut engine = Engine::with_config(Config {
+            header_row: HeaderRow::Absent,
+            ..Config::default()
+        });
+        let rejects = SharedBuf::default();
+        engine
+            .set_reject_log(RejectLog::new(Box::new(rejects.clone()), RejectFormat::Csv).unwrap());
+        let input = "type,client,tx,amount\ndeposit,1,1,2.5\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+        assert_eq!(engine.account(1).unwrap().total(), amount("2.5"));
+        assert_eq!(
+            rejects.contents(),
+            "line,byte,kind,reason,row\n1,0,record,Parse,\"type,client,tx,amount\"\n"
+        );
+    }
+
+    #[test]
+    fn test_process_reader_reordered_headers() {
+        let mut engine = Engine::with_config(Config {
+            header_aliases: vec![("kind".to_string(), Column::Type)],
+            ..Config::default()
+        });
+        let input = "TX, Client,channel,Kind,amount\n\
+                     1,1,card,deposit,2.5\n\
+                     2,1,wire,withdrawal,1.0\n\
+                     1,1,card,dispute\n";
+
+        engine.process_reader(input.as_bytes()).unwrap();
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.total(), amount("1.5"));
+        assert_eq!(cas.held(), amount("2.5"));
+    }
+
+    /// A writer we can still read from once the engine owns it.
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_reject_log_csv() {
+        let mut engine = Engine::new();
+        let buf = SharedBuf::default();
+        let log = RejectLog::new(Box::new(buf.clone()), RejectFormat::Csv).unwrap();
+        engine.set_reject_log(log);
+
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.5\n\
+                     withdrawal,1,2,10.0\n\
+                     teleport,1,3,1.0\n\
+                     deposit,1\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+
+        assert_eq!(
+            buf.contents(),
+            "line,byte,kind,reason,row\n\
+             3,38,db,NotEnoughAvailableCredit,\"withdrawal,1,2,10.0\"\n\
+             4,58,record,Parse,\"teleport,1,3,1.0\"\n\
+             5,75,record,MissingField,\"deposit,1\"\n"
+        );
+        assert_eq!(engine.account(1).unwrap().total(), amount("2.5"));
+    }
+
+    #[test]
+    fn test_reject_log_jsonl() {
+        let mut engine = Engine::new();
+        let buf = SharedBuf::default();
+        let log = RejectLog::new(Box::new(buf.clone()), RejectFormat::Jsonl).unwrap();
+        engine.set_reject_log(log);
+
+        let input = "type,client,tx,amount\nresolve,1,9,\n";
+        engine.process_reader(input.as_bytes()).unwrap();
+
+        assert_eq!(
+            buf.contents(),
+            "{\"line\":2,\"byte\":22,\"kind\":\"db\",\
+             \"reason\":\"TransactionNotFound\",\"row\":\"resolve,1,9,\"}\n"
+        );
+    }
+
+    /// Mock engine whose client 1 has been frozen, with a past deposit
+    /// (tx 3) still open to dispute.
+    fn mock_locked_engine(frozen_policy: FrozenPolicy) -> Engine {
+        let mut engine = mock_engine_with(Config {
+            frozen_policy,
+            ..Config::default()
+        });
+        let deposit = Record {
+            transaction_kind: TransactionKind::Deposit,
+            client: 1,
+            tx: 3,
+            amount: amount("5.0"),
+            destination: None,
+        };
+        engine.process_record(&deposit).unwrap();
+        let mut cas = engine.account(1).unwrap();
+        cas.lock();
+        engine.db.set_client(1, cas);
+        engine
+    }
+
+    fn record(transaction_kind: TransactionKind, tx: u32, amount: Amount) -> Record {
+        Record {
+            transaction_kind,
+            client: 1,
+            tx,
+            amount,
+            destination: None,
+        }
+    }
+
+    fn db_error(result: Result<()>) -> DBError {
+        match result.unwrap_err().into_kind() {
+            EngineErrorKind::DBError(err) => err,
+            other => panic!("expected a DBError, got {:?}", other),
+        }
+    }
+
+    fn assert_locked(result: Result<()>) {
+        assert_eq!(db_error(result), DBError::AccountLocked);
+    }
+
+    #[test]
+    fn test_frozen_reject_all() {
+        let mut engine = mock_locked_engine(FrozenPolicy::RejectAll);
+
+        assert_locked(engine.process_record(&record(TransactionKind::Deposit, 4, amount("1.0"))));
+        assert_locked(engine.process_record(&record(
+            TransactionKind::Withdrawal,
+            5,
+            amount("1.0"),
+        )));
+        assert_locked(engine.process_record(&record(TransactionKind::Dispute, 3, Amount::ZERO)));
+
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.total(), amount("15.0"));
+        assert_eq!(cas.held(), Amount::ZERO);
+        // Other clients are not affected.
+        engine
+            .process_record(&Record {
+                transaction_kind: TransactionKind::Deposit,
+                client: 2,
+                tx: 6,
+                amount: amount("1.0"),
+                destination: None,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_frozen_allow_deposits() {
+        let mut engine = mock_locked_engine(FrozenPolicy::AllowDeposits);
+
+        engine
+            .process_record(&record(TransactionKind::Deposit, 4, amount("1.0")))
+            .unwrap();
+        assert_locked(engine.process_record(&record(
+            TransactionKind::Withdrawal,
+            5,
+            amount("1.0"),
+        )));
+        assert_locked(engine.process_record(&record(TransactionKind::Dispute, 3, Amount::ZERO)));
+
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.total(), amount("16.0"));
+        assert!(cas.locked());
+    }
+
+    #[test]
+    fn test_frozen_allow_dispute_resolution() {
+        let mut engine = mock_locked_engine(FrozenPolicy::AllowDisputeResolution);
+
+        assert_locked(engine.process_record(&record(TransactionKind::Deposit, 4, amount("1.0"))));
+        assert_locked(engine.process_record(&record(
+            TransactionKind::Withdrawal,
+            5,
+            amount("1.0"),
+        )));
+        engine
+            .process_record(&record(TransactionKind::Dispute, 3, Amount::ZERO))
+            .unwrap();
+        engine
+            .process_record(&record(TransactionKind::Resolve, 3, Amount::ZERO))
+            .unwrap();
+
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.available(), amount("15.0"));
+        assert_eq!(cas.held(), Amount::ZERO);
+    }
+
+    /// Runs the dispute process `steps` over the mock deposit tx 1 of
+    /// client 1, returning the outcome of the last one.
+    fn dispute_steps(engine: &mut Engine, steps: &[TransactionKind]) -> Result<()> {
+        let (last, firsts) = steps.split_last().unwrap();
+        for &kind in firsts {
+            engine
+                .process_record(&record(kind, 1, Amount::ZERO))
+                .unwrap();
+        }
+        engine.process_record(&record(*last, 1, Amount::ZERO))
+    }
+
+    fn tx_state(engine: &Engine, tx: u32) -> TransactionState {
+        engine.db.transaction(tx).unwrap().unwrap().state()
+    }
+
+    #[test]
+    fn test_dispute_twice() {
+        use TransactionKind::*;
+        let mut engine = mock_engine();
+
+        let result = dispute_steps(&mut engine, &[Dispute, Dispute]);
+        assert_eq!(db_error(result), DBError::TransactionAlreadyDisputed);
+
+        // The funds are only held once.
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.held(), amount("10.0"));
+        assert_eq!(cas.available(), Amount::ZERO);
+        assert_eq!(tx_state(&engine, 1), TransactionState::Disputed);
+    }
+
+    #[test]
+    fn test_resolve_without_dispute() {
+        use TransactionKind::*;
+        let mut engine = mock_engine();
+
+        let result = dispute_steps(&mut engine, &[Resolve]);
+        assert_eq!(db_error(result), DBError::TransactionNotInDispute);
+
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.available(), amount("10.0"));
+        assert_eq!(cas.held(), Amount::ZERO);
+        assert_eq!(tx_state(&engine, 1), TransactionState::Processed);
+    }
+
+    #[test]
+    fn test_chargeback_without_dispute() {
+        use TransactionKind::*;
+        let mut engine = mock_engine();
+
+        let result = dispute_steps(&mut engine, &[Chargeback]);
+        assert_eq!(db_error(result), DBError::TransactionNotInDispute);
+
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.total(), amount("10.0"));
+        assert!(!cas.locked());
+        assert_eq!(tx_state(&engine, 1), TransactionState::Processed);
+    }
+
+    #[test]
+    fn test_after_resolve() {
+        use TransactionKind::*;
+
+        for kind in [Dispute, Resolve, Chargeback] {
+            let mut engine = mock_engine();
+            let result = dispute_steps(&mut engine, &[Dispute, Resolve, kind]);
+            assert_eq!(db_error(result), DBError::TransactionAlreadyResolved);
+
+            let cas = engine.account(1).unwrap();
+            assert_eq!(cas.available(), amount("10.0"));
+            assert_eq!(cas.held(), Amount::ZERO);
+            assert_eq!(tx_state(&engine, 1), TransactionState::Resolved);
+        }
+    }
+
+    #[test]
+    fn test_after_chargeback() {
+        use TransactionKind::*;
+
+        for kind in [Dispute, Resolve, Chargeback] {
+            // The account gets frozen by the chargeback, so let the
+            // dispute process through to reach the state machine.
+            let mut engine = mock_engine_with(Config {
+                frozen_policy: FrozenPolicy::AllowDisputeResolution,
+                ..Config::default()
+            });
+            let result = dispute_steps(&mut engine, &[Dispute, Chargeback, kind]);
+            assert_eq!(db_error(result), DBError::TransactionAlreadyChargedBack);
+
+            let cas = engine.account(1).unwrap();
+            assert_eq!(cas.total(), Amount::ZERO);
+            assert_eq!(cas.held(), Amount::ZERO);
+            assert_eq!(tx_state(&engine, 1), TransactionState::ChargedBack);
+        }
+    }
+
+    #[test]
+    fn test_dispute_client_mismatch() {
+        let mut engine = mock_engine();
+
+        // tx 2 belongs to client 2.
+        let result = engine.process_record(&record(TransactionKind::Dispute, 2, Amount::ZERO));
+        assert_eq!(db_error(result), DBError::ClientIdMismatch);
+        assert_eq!(tx_state(&engine, 2), TransactionState::Processed);
+        let cas = engine.account(2).unwrap();
+        assert_eq!(cas.held(), Amount::ZERO);
+    }
+
+    fn mock_withdrawal_engine(withdrawal_disputes: WithdrawalDisputePolicy) -> Engine {
+        let mut engine = mock_engine_with(Config {
+            withdrawal_disputes,
+            ..Config::default()
+        });
+        engine
+            .process_record(&record(TransactionKind::Withdrawal, 3, amount("4.0")))
+            .unwrap();
+        engine
+    }
+
+    fn assert_balances(engine: &Engine, available: &str, held: &str, total: &str) {
+        let cas = engine.account(1).unwrap();
+        assert_eq!(cas.available(), amount(available));
+        assert_eq!(cas.held(), amount(held));
+        assert_eq!(cas.total(), amount(total));
+    }
+
+    #[test]
+    fn test_withdrawal_is_stored() {
+        let engine = mock_withdrawal_engine(WithdrawalDisputePolicy::Refuse);
+
+        let trx = engine.db.transaction(3).unwrap().unwrap();
+        assert_eq!(trx.kind(), TransactionKind::Withdrawal);
+        assert_eq!(trx.amount(), amount("4.0"));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_refused() {
+        let mut engine = mock_withdrawal_engine(WithdrawalDisputePolicy::Refuse);
+
+        let result = engine.process_record(&record(TransactionKind::Dispute, 3, Amount::ZERO));
+        assert_eq!(db_error(result), DBError::OperationNotPermitted);
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+        assert_eq!(tx_state(&engine, 3), TransactionState::Processed);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_resolve() {
+        let mut engine = mock_withdrawal_engine(WithdrawalDisputePolicy::Credit);
+
+        engine
+            .process_record(&record(TransactionKind::Dispute, 3, Amount::ZERO))
+            .unwrap();
+        assert_balances(&engine, "6.0", "4.0", "10.0");
+
+        engine
+            .process_record(&record(TransactionKind::Resolve, 3, Amount::ZERO))
+            .unwrap();
+        // The withdrawal stands.
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+        assert_eq!(tx_state(&engine, 3), TransactionState::Resolved);
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_chargeback() {
+        let mut engine = mock_withdrawal_engine(WithdrawalDisputePolicy::Credit);
+
+        engine
+            .process_record(&record(TransactionKind::Dispute, 3, Amount::ZERO))
+            .unwrap();
+        engine
+            .process_record(&record(TransactionKind::Chargeback, 3, Amount::ZERO))
+            .unwrap();
+        // The withdrawn funds are given back to the client.
+        assert_balances(&engine, "10.0", "0.0", "10.0");
+        assert!(engine.account(1).unwrap().locked());
+        assert_eq!(tx_state(&engine, 3), TransactionState::ChargedBack);
+    }
+
+    #[test]
+    fn test_withdrawal_within_credit_limit() {
+        let mut credit_limits = CreditLimits {
+            default: amount("5.0"),
+            ..CreditLimits::default()
+        };
+        credit_limits.clients.insert(2, Amount::ZERO);
+        let mut engine = mock_engine_with(Config {
+            credit_limits,
+            ..Config::default()
+        });
+
+        // Client 1 holds 10 and may go down to -5.
+        engine
+            .process_record(&record(TransactionKind::Withdrawal, 3, amount("12.0")))
+            .unwrap();
+        assert_balances(&engine, "-2.0", "0.0", "-2.0");
+        let result = engine.process_record(&record(TransactionKind::Withdrawal, 4, amount("3.1")));
+        assert_eq!(db_error(result), DBError::NotEnoughAvailableCredit);
+        engine
+            .process_record(&record(TransactionKind::Withdrawal, 5, amount("3.0")))
+            .unwrap();
+        assert_balances(&engine, "-5.0", "0.0", "-5.0");
+
+        // Client 2 has no overdraft.
+        let withdrawal = Record {
+            client: 2,
+            ..record(TransactionKind::Withdrawal, 6, amount("20.1"))
+        };
+        let result = engine.process_record(&withdrawal);
+        assert_eq!(db_error(result), DBError::NotEnoughAvailableCredit);
+
+        let mut report = Vec::new();
+        engine
+            .write_db(&mut report, &OutputOptions::default())
+            .unwrap();
+        assert!(String::from_utf8(report)
+            .unwrap()
+            .contains("1,-5.0000,0.0000,-5.0000,false"));
+    }
+
+    #[test]
+    fn test_unknown_client_or_transaction_reported() {
+        let mut engine = mock_engine();
+        let withdrawal = Record {
+            client: 9,
+            ..record(TransactionKind::Withdrawal, 3, amount("1.0"))
+        };
+        let result = engine.process_record(&withdrawal);
+        assert_eq!(db_error(result), DBError::ClientNotFound);
+        assert!(engine.account(9).is_none());
+        assert!(engine.db.transaction(3).unwrap().is_none());
+
+        let result = engine.process_record(&record(TransactionKind::Chargeback, 7, Amount::ZERO));
+        assert_eq!(db_error(result), DBError::TransactionNotFound);
+        assert_balances(&engine, "10.0", "0.0", "10.0");
+    }
+
+    #[test]
+    fn test_client_creation() {
+        let for_client = |kind, tx, value| Record {
+            client: 9,
+            ..record(kind, tx, amount(value))
+        };
+
+        let mut engine = mock_engine_with(Config {
+            client_creation: ClientCreation::Never,
+            ..Config::default()
+        });
+        let result = engine.process_record(&for_client(TransactionKind::Deposit, 3, "1.0"));
+        assert_eq!(db_error(result), DBError::ClientNotFound);
+        assert!(engine.account(9).is_none());
+        engine
+            .process_record(&record(TransactionKind::Deposit, 4, amount("1.0")))
+            .unwrap();
+        assert_balances(&engine, "11.0", "0.0", "11.0");
+
+        let mut engine = mock_engine_with(Config {
+            client_creation: ClientCreation::Always,
+            credit_limits: CreditLimits {
+                default: amount("5.0"),
+                ..CreditLimits::default()
+            },
+            ..Config::default()
+        });
+        // Refused, so no account is opened.
+        let result = engine.process_record(&for_client(TransactionKind::Withdrawal, 3, "6.0"));
+        assert_eq!(db_error(result), DBError::NotEnoughAvailableCredit);
+        assert!(engine.account(9).is_none());
+        engine
+            .process_record(&for_client(TransactionKind::Withdrawal, 4, "3.0"))
+            .unwrap();
+        assert_eq!(engine.account(9).unwrap().available(), amount("-3.0"));
+    }
+
+    #[test]
+    fn test_duplicate_rejected_before_any_change() {
+        use TransactionKind::*;
+        let mut engine = mock_withdrawal_engine(WithdrawalDisputePolicy::Refuse);
+
+        // A replayed deposit or withdrawal, or another kind reusing the id.
+        for (kind, tx) in [(Deposit, 1), (Withdrawal, 3), (Withdrawal, 1), (Deposit, 3)] {
+            let result = engine.process_record(&record(kind, tx, amount("4.0")));
+            assert_eq!(db_error(result), DBError::TransactionAlreadyExists);
+        }
+        // Another client's id is taken as well.
+        let result = engine.process_record(&record(Deposit, 2, amount("20.0")));
+        assert_eq!(db_error(result), DBError::TransactionAlreadyExists);
+
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+        assert_eq!(engine.account(2).unwrap().total(), amount("20.0"));
+        assert_eq!(
+            engine.db.transaction(3).unwrap().unwrap().amount(),
+            amount("4.0")
+        );
+    }
 
-        assert_eq!(cas.locked(), true);
-        assert_eq!(cas.held(), 0.0);
-        assert_eq!(cas.available(), 10.0);
-        assert_eq!(cas.total(), 10.0);
+    #[test]
+    fn test_duplicate_ignored() {
+        use TransactionKind::*;
+        let mut engine = mock_engine_with(Config {
+            duplicates: DuplicatePolicy::Ignore,
+            ..Config::default()
+        });
+        engine
+            .process_record(&record(Withdrawal, 3, amount("4.0")))
+            .unwrap();
+
+        // Exact repeats are skipped as already applied.
+        engine
+            .process_record(&record(Deposit, 1, amount("10.0")))
+            .unwrap();
+        engine
+            .process_record(&record(Withdrawal, 3, amount("4.0")))
+            .unwrap();
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+
+        // Anything else reusing an id is still refused.
+        for (kind, tx, value) in [
+            (Deposit, 1, "9.0"),
+            (Deposit, 3, "4.0"),
+            (Deposit, 2, "20.0"),
+        ] {
+            let result = engine.process_record(&record(kind, tx, amount(value)));
+            assert_eq!(db_error(result), DBError::TransactionAlreadyExists);
+        }
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+    }
 
-        let tx = engine.db.get_transaction_db().get(&3).unwrap();
-        assert_eq!(tx.is_in_dispute(), true);
+    #[test]
+    fn test_transfer() {
+        let mut engine = mock_engine();
+        engine
+            .process_record(&Record::transfer(1, 3, 2, amount("4.0")))
+            .unwrap();
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+        assert_eq!(engine.account(2).unwrap().total(), amount("24.0"));
+        let trx = engine.transaction(3).unwrap().unwrap();
+        assert_eq!(trx.kind(), TransactionKind::Transfer);
+        assert_eq!(trx.destination(), Some(2));
+
+        // An unknown receiver is opened an account, as a deposit would.
+        engine
+            .process_record(&Record::transfer(1, 4, 9, amount("1.0")))
+            .unwrap();
+        assert_eq!(engine.account(9).unwrap().available(), amount("1.0"));
+
+        // Refused transfers change neither side.
+        let before = state_of(&engine);
+        let result = engine.process_record(&Record::transfer(1, 5, 2, amount("5.1")));
+        assert_eq!(db_error(result), DBError::NotEnoughAvailableCredit);
+        let result = engine.process_record(&Record::transfer(1, 3, 2, amount("1.0")));
+        assert_eq!(db_error(result), DBError::TransactionAlreadyExists);
+        let result = engine.process_record(&Record::transfer(1, 5, 1, amount("1.0")));
+        assert!(matches!(
+            result.unwrap_err().into_kind(),
+            EngineErrorKind::RecordError(RecordError::Invalid)
+        ));
+        let mut cas = engine.account(2).unwrap();
+        cas.lock();
+        engine.db.set_client(2, cas);
+        let locked = state_of(&engine);
+        assert_locked(engine.process_record(&Record::transfer(1, 5, 2, amount("1.0"))));
+        assert_eq!(state_of(&engine), locked);
+        cas.unlock();
+        engine.db.set_client(2, cas);
+        assert_eq!(state_of(&engine), before);
+
+        let mut engine = mock_engine_with(Config {
+            client_creation: ClientCreation::Never,
+            ..Config::default()
+        });
+        let result = engine.process_record(&Record::transfer(1, 3, 9, amount("1.0")));
+        assert_eq!(db_error(result), DBError::ClientNotFound);
+        assert_balances(&engine, "10.0", "0.0", "10.0");
+        assert!(engine.account(9).is_none());
+    }
+
+    #[test]
+    fn test_transfer_dispute() {
+        use TransactionKind::*;
+        let transferred = || {
+            let mut engine = mock_engine();
+            engine
+                .process_record(&Record::transfer(1, 3, 2, amount("4.0")))
+                .unwrap();
+            engine
+        };
+        let account = |engine: &Engine, client| {
+            let cas = engine.account(client).unwrap();
+            (cas.available(), cas.held(), cas.total(), cas.locked())
+        };
+
+        // Disputed by its sender, whatever the withdrawal dispute policy, the
+        // funds being held on the receiving side.
+        let mut engine = transferred();
+        let result = engine.process_record(&Record {
+            client: 2,
+            ..record(Dispute, 3, Amount::ZERO)
+        });
+        assert_eq!(db_error(result), DBError::ClientIdMismatch);
+        engine
+            .process_record(&record(Dispute, 3, Amount::ZERO))
+            .unwrap();
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+        assert_eq!(
+            account(&engine, 2),
+            (amount("20.0"), amount("4.0"), amount("24.0"), false)
+        );
+        assert_eq!(tx_state(&engine, 3), TransactionState::Disputed);
+
+        engine
+            .process_record(&record(Resolve, 3, Amount::ZERO))
+            .unwrap();
+        assert_balances(&engine, "6.0", "0.0", "6.0");
+        assert_eq!(
+            account(&engine, 2),
+            (amount("24.0"), Amount::ZERO, amount("24.0"), false)
+        );
+
+        // A chargeback reverses both sides at once.
+        let mut engine = transferred();
+        engine
+            .process_record(&record(Dispute, 3, Amount::ZERO))
+            .unwrap();
+        engine
+            .process_record(&record(Chargeback, 3, Amount::ZERO))
+            .unwrap();
+        assert_balances(&engine, "10.0", "0.0", "10.0");
+        assert!(engine.account(1).unwrap().locked());
+        assert_eq!(
+            account(&engine, 2),
+            (amount("20.0"), Amount::ZERO, amount("20.0"), false)
+        );
+        assert_eq!(tx_state(&engine, 3), TransactionState::ChargedBack);
+    }
+
+    /// Generates a pseudo random transactions file. Deposits may reuse the
+    /// id of a stored transaction, their owner's or another client's, and
+    /// disputes may refer to any transaction, known or not.
+    fn generate_input(seed: u64, rows: usize) -> String {
+        // xorshift64*, enough for test data.
+        let mut state = seed.max(1);
+        let mut next = move |bound: u64| {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(0x2545_f491_4f6c_dd1d) % bound
+        };
+
+        let mut input = String::from("type,client,tx,amount,destination\n");
+        let mut stored: Vec<(u32, u16)> = Vec::new();
+        for tx in 1..=rows as u32 {
+            let client = next(12) as u16 + 1;
+            let amount = next(5_000_000);
+            let amount = format!("{}.{:04}", amount / 10_000, amount % 10_000);
+            let roll = next(100);
+            let row = match roll {
+                0..=59 => {
+                    let kind = if roll < 45 { "deposit" } else { "withdrawal" };
+                    stored.push((tx, client));
+                    format!("{},{},{},{}", kind, client, tx, amount)
+                }
+                60..=64 => {
+                    // Now and then to oneself, which is rejected.
+                    let destination = next(12) as u16 + 1;
+                    stored.push((tx, client));
+                    format!("transfer,{},{},{},{}", client, tx, amount, destination)
+                }
+                65..=91 if !stored.is_empty() => {
+                    let (tx, owner) = stored[next(stored.len() as u64) as usize];
+                    let kind = match roll {
+                        65..=77 => "dispute",
+                        78..=85 => "resolve",
+                        _ => "chargeback",
+                    };
+                    // Mostly the owner, sometimes someone else.
+                    let client = if next(10) == 0 { client } else { owner };
+                    format!("{},{},{},", kind, client, tx)
+                }
+                92..=95 if !stored.is_empty() => {
+                    let (tx, owner) = stored[next(stored.len() as u64) as usize];
+                    // Its owner replaying it, or someone else reusing it.
+                    let client = if next(2) == 0 { client } else { owner };
+                    format!("deposit,{},{},{}", client, tx, amount)
+                }
+                96 => format!("dispute,{},{},", client, u32::MAX - tx),
+                97 => {
+                    // A first use of the id that is rejected, by an unknown
+                    // client or for lack of funds, then the next client,
+                    // on another shard, reusing it.
+                    let first = if next(2) == 0 {
+                        format!("withdrawal,{},{},{}", client + 100, tx, amount)
+                    } else {
+                        format!("withdrawal,{},{},1000000.0", client, tx)
+                    };
+                    let client = client % 12 + 1;
+                    stored.push((tx, client));
+                    format!("{}\ndeposit,{},{},{}", first, client, tx, amount)
+                }
+                _ => format!("deposit,{},oops,{}", client, amount),
+            };
+            input.push_str(&row);
+            input.push('\n');
+        }
+        input
+    }
+
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let configs = [
+            Config::default(),
+            Config {
+                frozen_policy: FrozenPolicy::AllowDisputeResolution,
+                withdrawal_disputes: WithdrawalDisputePolicy::Credit,
+                client_creation: ClientCreation::Always,
+                ..Config::default()
+            },
+        ];
+
+        for seed in 1..=4 {
+            let input = generate_input(seed, 5_000);
+            for config in configs.iter() {
+                // Whole entries, reasons included, in no particular order.
+                let rejected_rows = |buf: &SharedBuf| {
+                    let mut rows: Vec<String> = buf.contents().lines().map(String::from).collect();
+                    rows.sort();
+                    rows
+                };
+
+                let mut sequential = Engine::with_config(config.clone());
+                let seq_rejects = SharedBuf::default();
+                let log = RejectLog::new(Box::new(seq_rejects.clone()), RejectFormat::Csv);
+                sequential.set_reject_log(log.unwrap());
+                sequential.process_reader(input.as_bytes()).unwrap();
+                let mut expected = Vec::new();
+                sequential
+                    .write_db(&mut expected, &OutputOptions::default())
+                    .unwrap();
+
+                for shards in [2, 3, 7] {
+                    let mut parallel = Engine::with_config(config.clone());
+                    let par_rejects = SharedBuf::default();
+                    let log = RejectLog::new(Box::new(par_rejects.clone()), RejectFormat::Csv);
+                    parallel.set_reject_log(log.unwrap());
+                    parallel
+                        .process_reader_parallel(input.as_bytes(), shards)
+                        .unwrap();
+                    let mut out = Vec::new();
+                    parallel
+                        .write_db(&mut out, &OutputOptions::default())
+                        .unwrap();
+
+                    assert_eq!(
+                        String::from_utf8(out).unwrap(),
+                        String::from_utf8(expected.clone()).unwrap(),
+                        "seed {}, {} shards, {:?}",
+                        seed,
+                        shards,
+                        config
+                    );
+                    assert_eq!(rejected_rows(&par_rejects), rejected_rows(&seq_rejects));
+                    for (tx, trx) in sequential.db.transactions().map(|t| t.unwrap()) {
+                        assert_eq!(parallel.transaction(tx).unwrap(), Some(trx));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parallel_resumes_from_state() {
+        let mut engine = mock_engine();
+        let input = "type,client,tx,amount\n\
+                     dispute,1,1,\n\
+                     deposit,2,3,1.0\n\
+                     deposit,3,4,1.0\n";
+        engine.process_reader_parallel(input.as_bytes(), 4).unwrap();
+
+        assert_balances(&engine, "0.0", "10.0", "10.0");
+        assert_eq!(engine.account(2).unwrap().total(), amount("21.0"));
+        assert_eq!(engine.account(3).unwrap().total(), amount("1.0"));
+        assert_eq!(tx_state(&engine, 1), TransactionState::Disputed);
+    }
+
+    #[test]
+    fn test_parallel_unique_transaction_ids() {
+        // Clients 1 and 3 land on a shard, 2 and 4 on the other one.
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,10.0\n\
+                     deposit,2,1,5.0\n\
+                     dispute,2,1,\n\
+                     deposit,4,5,1.0\n\
+                     withdrawal,3,5,1.0\n\
+                     withdrawal,1,6,100.0\n\
+                     deposit,2,6,2.0\n\
+                     dispute,1,6,\n\
+                     dispute,1,1,\n";
+        let run = |shards| {
+            let mut engine = Engine::new();
+            let rejects = SharedBuf::default();
+            let log = RejectLog::new(Box::new(rejects.clone()), RejectFormat::Csv);
+            engine.set_reject_log(log.unwrap());
+            engine
+                .process_reader_parallel(input.as_bytes(), shards)
+                .unwrap();
+            let mut rejects: Vec<String> = rejects.contents().lines().map(String::from).collect();
+            rejects.sort();
+            (engine, rejects)
+        };
+
+        let (sequential, expected) = run(1);
+        let (parallel, rejects) = run(2);
+        assert_eq!(rejects, expected);
+        // The header and five rows.
+        assert_eq!(rejects.len(), 6);
+        assert_eq!(
+            parallel.accounts().collect::<Vec<_>>(),
+            sequential.accounts().collect::<Vec<_>>()
+        );
+        assert_balances(&parallel, "0.0", "10.0", "10.0");
+        assert_eq!(parallel.account(2).unwrap().total(), amount("2.0"));
+        assert_eq!(parallel.transaction(1).unwrap().unwrap().client_id(), 1);
+        assert_eq!(parallel.transaction(6).unwrap().unwrap().client_id(), 2);
     }
 }
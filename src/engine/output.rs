@@ -0,0 +1,315 @@
+//! Client account report writer. The report is a proper csv document by
+//! default, the legacy space padded layout being kept as an option, or JSON
+//! for the services downstream.
+
+use super::db::client::ClientAccountState;
+#[cfg(test)]
+use super::db::client::ClientDB;
+use super::error::Result;
+use std::io;
+
+/// Columns of the client account report.
+const HEADER: [&str; 5] = ["client", "available", "held", "total", "locked"];
+/// Header of the legacy space padded layout.
+const LEGACY_HEADER: &str = "client, available, held, total, locked";
+
+/// Document format of the client account report.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A single JSON array of accounts.
+    Json,
+    /// One JSON account object per line.
+    Jsonl,
+}
+
+impl OutputFormat {
+    /// Returns a format from its command line name, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            "jsonl" => Some(Self::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+/// When fields of the report get quoted.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Quoting {
+    /// Only when a field needs it.
+    #[default]
+    Necessary,
+    /// Every field.
+    Always,
+    /// Never, even if the output ends up ambiguous.
+    Never,
+    /// Every field that isn't a number.
+    NonNumeric,
+}
+
+impl Quoting {
+    /// Returns a quoting style from its command line name, or None if unknown.
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "necessary" => Some(Self::Necessary),
+            "always" => Some(Self::Always),
+            "never" => Some(Self::Never),
+            "non-numeric" => Some(Self::NonNumeric),
+            _ => None,
+        }
+    }
+
+    fn quote_style(self) -> csv::QuoteStyle {
+        match self {
+            Self::Necessary => csv::QuoteStyle::Necessary,
+            Self::Always => csv::QuoteStyle::Always,
+            Self::Never => csv::QuoteStyle::Never,
+            Self::NonNumeric => csv::QuoteStyle::NonNumeric,
+        }
+    }
+}
+
+/// How the client account report is laid out.
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    /// Csv only.
+    pub delimiter: u8,
+    /// Csv only.
+    pub quoting: Quoting,
+    /// Writes the space padded layout we used to print instead of csv.
+    /// Delimiter and quoting are then ignored. Csv only.
+    pub legacy: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::default(),
+            delimiter: b',',
+            quoting: Quoting::default(),
+            legacy: false,
+        }
+    }
+}
+
+/// Writes the state of every client's account to `wtr`, in the order
+/// `accounts` yields them.
+pub fn write_report<W, I>(accounts: I, wtr: W, options: &OutputOptions) -> Result<()>
+where
+    W: io::Write,
+    I: IntoIterator<Item = (u16, ClientAccountState)>,
+{
+    let db = accounts.into_iter();
+    match options.format {
+        OutputFormat::Csv if options.legacy => write_legacy(db, wtr),
+        OutputFormat::Csv => write_csv(db, wtr, options),
+        OutputFormat::Json => write_json(db, wtr),
+        OutputFormat::Jsonl => write_jsonl(db, wtr),
+    }
+}
+
+fn write_csv<W: io::Write>(
+    db: impl Iterator<Item = (u16, ClientAccountState)>,
+    wtr: W,
+    options: &OutputOptions,
+) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(options.delimiter)
+        .quote_style(options.quoting.quote_style())
+        .from_writer(wtr);
+
+    wtr.write_record(HEADER)?;
+    for (key, value) in db {
+        wtr.write_record([
+            key.to_string(),
+            value.available().to_string(),
+            value.held().to_string(),
+            value.total().to_string(),
+            value.locked().to_string(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_legacy<W: io::Write>(
+    db: impl Iterator<Item = (u16, ClientAccountState)>,
+    mut wtr: W,
+) -> Result<()> {
+    writeln!(wtr, "{}", LEGACY_HEADER)?;
+    for (key, value) in db {
+        writeln!(
+            wtr,
+            "{:>6}, {:>9}, {:>4}, {:>5}, {:>6}",
+            key,
+            value.available(),
+            value.held(),
+            value.total(),
+            value.locked()
+        )?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_json<W: io::Write>(
+    db: impl Iterator<Item = (u16, ClientAccountState)>,
+    mut wtr: W,
+) -> Result<()> {
+    let mut empty = true;
+    wtr.write_all(b"[")?;
+    for (key, value) in db {
+        wtr.write_all(if empty { b"\n  " } else { b",\n  " })?;
+        write_json_account(&mut wtr, key, &value)?;
+        empty = false;
+    }
+    wtr.write_all(if empty { b"]\n" } else { b"\n]\n" })?;
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_jsonl<W: io::Write>(
+    db: impl Iterator<Item = (u16, ClientAccountState)>,
+    mut wtr: W,
+) -> Result<()> {
+    for (key, value) in db {
+        write_json_account(&mut wtr, key, &value)?;
+        wtr.write_all(b"\n")?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes a single account as a JSON object. Amounts are written as strings
+/// so no precision is lost to a float on the consumer side.
+pub fn write_json_account<W: io::Write>(
+    wtr: &mut W,
+    client: u16,
+    cas: &ClientAccountState,
+) -> io::Result<()> {
+    write!(
+        wtr,
+        "{{\"client\":{},\"available\":\"{}\",\"held\":\"{}\",\"total\":\"{}\",\"locked\":{}}}",
+        client,
+        cas.available(),
+        cas.held(),
+        cas.total(),
+        cas.locked()
+    )
+}
+
+#[cfg(test)]
+fn mock_db() -> ClientDB {
+    let mut db = ClientDB::new();
+    let mut cas = ClientAccountState::new();
+    cas.add("1.5".parse().unwrap()).unwrap();
+    db.insert(1, cas);
+    let mut cas = ClientAccountState::new();
+    cas.add("419".parse().unwrap()).unwrap();
+    cas.lock();
+    db.insert(2, cas);
+    db
+}
+
+#[test]
+fn test_write_report() {
+    let mut out = Vec::new();
+    write_report(mock_db(), &mut out, &OutputOptions::default()).unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client,available,held,total,locked\n\
+         1,1.5000,0.0000,1.5000,false\n\
+         2,419.0000,0.0000,419.0000,true\n"
+    );
+}
+
+#[test]
+fn test_write_report_delimiter_and_quoting() {
+    let options = OutputOptions {
+        delimiter: b';',
+        quoting: Quoting::Always,
+        ..OutputOptions::default()
+    };
+    let mut out = Vec::new();
+    write_report(mock_db(), &mut out, &options).unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap().lines().nth(1).unwrap(),
+        "\"1\";\"1.5000\";\"0.0000\";\"1.5000\";\"false\""
+    );
+}
+
+#[test]
+fn test_write_report_legacy() {
+    let options = OutputOptions {
+        legacy: true,
+        ..OutputOptions::default()
+    };
+    let mut out = Vec::new();
+    write_report(mock_db(), &mut out, &options).unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "client, available, held, total, locked\n\
+         \x20    1,    1.5000, 0.0000, 1.5000,  false\n\
+         \x20    2,  419.0000, 0.0000, 419.0000,   true\n"
+    );
+}
+
+#[test]
+fn test_write_report_json() {
+    let options = OutputOptions {
+        format: OutputFormat::Json,
+        ..OutputOptions::default()
+    };
+    let mut out = Vec::new();
+    write_report(mock_db(), &mut out, &options).unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "[\n  \
+         {\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false},\n  \
+         {\"client\":2,\"available\":\"419.0000\",\"held\":\"0.0000\",\"total\":\"419.0000\",\"locked\":true}\n\
+         ]\n"
+    );
+
+    let mut out = Vec::new();
+    write_report(ClientDB::new(), &mut out, &options).unwrap();
+    assert_eq!(out, b"[]\n");
+}
+
+#[test]
+fn test_write_report_jsonl() {
+    let options = OutputOptions {
+        format: OutputFormat::Jsonl,
+        ..OutputOptions::default()
+    };
+    let mut out = Vec::new();
+    write_report(mock_db(), &mut out, &options).unwrap();
+
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}\n\
+         {\"client\":2,\"available\":\"419.0000\",\"held\":\"0.0000\",\"total\":\"419.0000\",\"locked\":true}\n"
+    );
+}
+
+#[test]
+fn test_output_format_parsing() {
+    assert_eq!(OutputFormat::new("csv"), Some(OutputFormat::Csv));
+    assert_eq!(OutputFormat::new("json"), Some(OutputFormat::Json));
+    assert_eq!(OutputFormat::new("jsonl"), Some(OutputFormat::Jsonl));
+    assert_eq!(OutputFormat::new("xml"), None);
+}
+
+#[test]
+fn test_quoting_parsing() {
+    assert_eq!(Quoting::new("always"), Some(Quoting::Always));
+    assert_eq!(Quoting::new("non-numeric"), Some(Quoting::NonNumeric));
+    assert_eq!(Quoting::new("sometimes"), None);
+}
@@ -0,0 +1,455 @@
+//! Parallel processing. Rows only need to be applied in order for a given
+//! client, so the input is spread over shards by client id, each shard
+//! running its own engine on its own thread.
+//!
+//! A transfer, or a dispute process over one, changes two clients that may
+//! sit on different shards, and so does a row reusing the transaction id of
+//! a client sitting on another shard. Such a row ends the current round:
+//! once every shard is done with the rows before it, the row is applied on
+//! the shard holding the transaction, the accounts of the other clients
+//! being moved over for the time being, and a new round starts.
+
+use super::columns::{Column, ColumnMap, Rows};
+use super::db::DBError;
+use super::error::{EngineError, Result};
+use super::protocol::TransactionKind;
+use super::record::Record;
+use super::Engine;
+use csv::ByteRecord;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::panic;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread;
+
+/// How many records are handed over to a shard at once.
+const BATCH_SIZE: usize = 1024;
+/// How many batches may wait for a shard before the reader blocks.
+const QUEUED_BATCHES: usize = 16;
+
+/// Rows for a shard, parsed on the shard's own thread.
+type Batch = Vec<ByteRecord>;
+
+/// A row a shard rejected, raw if the reader keeps a rejects log.
+type Rejected = (Option<ByteRecord>, EngineError);
+
+/// Shard engines, along with the client each transaction id was handed
+/// over with.
+pub(crate) struct Shards {
+    engines: Vec<Engine>,
+    claims: HashMap<u32, Claim>,
+}
+
+/// The client a transaction id was first handed over with.
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    client: u16,
+    transfer: bool,
+}
+
+impl Engine {
+    /// Reads the csv file, decompressed on the fly if need be, and processes
+    /// it over `shards` threads, see [`Engine::process_reader_parallel`].
+    pub fn process_parallel<P: AsRef<Path>>(&mut self, path: P, shards: usize) -> Result<()> {
+        self.process_files(&[path], shards).map(|_| ())
+    }
+
+    /// Processes csv transactions like [`Engine::process_reader`], the rows
+    /// being spread over `shards` threads by client id. Every client's rows
+    /// are still applied in order, so the resulting accounts are the same.
+    ///
+    /// Rejected rows are reported in no particular order.
+    ///
+    /// The shards keep their transactions in memory, whatever the storage
+    /// backend, until they are merged back at the end. The reader keeps the
+    /// client each transaction id was handed over with. Every transfer,
+    /// every dispute, resolve or chargeback of one, and every row referring
+    /// to an id handed over to another shard waits for the shards to be
+    /// done with the rows before it, so inputs made of many transfers are
+    /// better processed sequentially in the first place.
+    ///
+    /// The journal relies on the rows order, so with one the rows are
+    /// processed sequentially.
+    pub fn process_reader_parallel<R: io::Read>(&mut self, reader: R, shards: usize) -> Result<()> {
+        if shards <= 1 || self.journal.is_some() {
+            return self.process_reader(reader);
+        }
+
+        let mut sharded = self.shard(shards)?;
+        self.process_sharded(reader, &mut sharded)?;
+        self.unshard(sharded)
+    }
+
+    /// Spreads the accounts and transactions over `shards` engines, ready
+    /// for [`Engine::process_sharded`] to run over one input after another.
+    pub(crate) fn shard(&self, shards: usize) -> Result<Shards> {
+        let engines = self.split(shards)?;
+        let mut claims = HashMap::new();
+        for stored in self.db.transactions() {
+            let (tx, trx) = stored?;
+            let claim = Claim {
+                client: trx.client_id(),
+                transfer: trx.kind() == TransactionKind::Transfer,
+            };
+            claims.insert(tx, claim);
+        }
+        Ok(Shards { engines, claims })
+    }
+
+    /// Processes the csv transactions over the `sharded` engines, which are
+    /// left for the next input or [`Engine::unshard`].
+    pub(crate) fn process_sharded<R: io::Read>(
+        &mut self,
+        reader: R,
+        sharded: &mut Shards,
+    ) -> Result<()> {
+        let mut rows = self.csv_reader(reader)?;
+        loop {
+            let engines = mem::take(&mut sharded.engines);
+            let (back, barrier) = self.run_round(&mut rows, engines, &mut sharded.claims)?;
+            sharded.engines = back;
+            match barrier {
+                Some(row) => self.apply_spanning(
+                    &mut sharded.engines,
+                    &mut sharded.claims,
+                    row,
+                    &rows.columns,
+                )?,
+                None => break,
+            }
+        }
+        if let Some(log) = self.rejects.as_mut() {
+            log.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Brings the `sharded` accounts and transactions back in.
+    pub(crate) fn unshard(&mut self, sharded: Shards) -> Result<()> {
+        self.merge(sharded.engines)
+    }
+
+    /// Runs the shard `engines` over the rows until the input is over or a
+    /// row spans shards, see [`Engine::dispatch`]. Returns the engines along
+    /// with the row that ended the round, if any.
+    fn run_round<R: io::Read>(
+        &mut self,
+        rows: &mut Rows<R>,
+        engines: Vec<Engine>,
+        claims: &mut HashMap<u32, Claim>,
+    ) -> Result<(Vec<Engine>, Option<ByteRecord>)> {
+        let columns = rows.columns;
+        let shards = engines.len();
+        let (rejected_tx, rejected_rx) = mpsc::channel();
+
+        let round = thread::scope(|scope| {
+            let mut queues = Vec::with_capacity(shards);
+            let mut workers = Vec::with_capacity(shards);
+            let log_rejects = self.rejects.is_some();
+            for engine in engines {
+                let (queue, batches) = mpsc::sync_channel(QUEUED_BATCHES);
+                let rejected = rejected_tx.clone();
+                workers.push(
+                    scope.spawn(move || run_shard(engine, batches, rejected, log_rejects, columns)),
+                );
+                queues.push(queue);
+            }
+            drop(rejected_tx);
+
+            let read = self.dispatch(rows, &queues, &rejected_rx, claims);
+            // Lets the shards know the round is over.
+            drop(queues);
+
+            let mut engines = Vec::with_capacity(shards);
+            for worker in workers {
+                match worker.join() {
+                    Ok(engine) => engines.push(engine?),
+                    Err(cause) => panic::resume_unwind(cause),
+                }
+            }
+            read.map(|barrier| (engines, barrier))
+        })?;
+
+        for (raw, err) in rejected_rx {
+            self.reject(raw, err)?;
+        }
+        Ok(round)
+    }
+
+    /// Applies a row spanning shards on the one holding its transaction, or
+    /// else on the one of its client, the accounts of the other clients it
+    /// involves being moved over from their own shard for the time being.
+    fn apply_spanning(
+        &mut self,
+        engines: &mut [Engine],
+        claims: &mut HashMap<u32, Claim>,
+        mut row: ByteRecord,
+        columns: &ColumnMap,
+    ) -> Result<()> {
+        self.tally.rows += 1;
+        let raw = self.rejects.as_ref().map(|_| row.clone());
+        let record = match Record::from_columns(&mut row, columns) {
+            Ok(record) => record,
+            Err(err) => return self.reject(raw, err.into()),
+        };
+
+        let home = |client: u16| usize::from(client) % engines.len();
+        // A claimed id is only ever stored on the shard of its claim.
+        let holder = match claims.get(&record.tx) {
+            Some(claim) => {
+                let shard = home(claim.client);
+                engines[shard]
+                    .db
+                    .transaction(record.tx)?
+                    .map(|trx| (shard, trx))
+            }
+            None => None,
+        };
+        let shard = holder.map_or(home(record.client), |(shard, _)| shard);
+        let away: Vec<(u16, usize)> = [
+            Some(record.client),
+            record.destination,
+            holder.and_then(|(_, trx)| trx.destination()),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|client| (client, home(client)))
+        .filter(|(_, home)| *home != shard)
+        .collect();
+
+        for (client, home) in away.iter().copied() {
+            if let Some(cas) = engines[home].db.client(client) {
+                engines[shard].db.set_client(client, cas);
+            }
+        }
+        let outcome = engines[shard].process_record(&record);
+        // The copies left behind are ignored when merging.
+        for (client, home) in away.iter().copied() {
+            if let Some(cas) = engines[shard].db.client(client) {
+                engines[home].db.set_client(client, cas);
+            }
+        }
+
+        match outcome {
+            Ok(()) => {
+                let kind = record.transaction_kind;
+                if holder.is_none()
+                    && matches!(
+                        kind,
+                        TransactionKind::Deposit
+                            | TransactionKind::Withdrawal
+                            | TransactionKind::Transfer
+                    )
+                {
+                    let claim = Claim {
+                        client: record.client,
+                        transfer: kind == TransactionKind::Transfer,
+                    };
+                    claims.insert(record.tx, claim);
+                }
+                Ok(())
+            }
+            Err(err) => self.reject(raw, err),
+        }
+    }
+
+    /// Reads the rows and hands them over to the shards in batches,
+    /// reporting the ones they reject on the way. Stops at a row spanning
+    /// shards, see [`spans_shards`], and returns it without counting it.
+    fn dispatch<R: io::Read>(
+        &mut self,
+        rows: &mut Rows<R>,
+        queues: &[SyncSender<Batch>],
+        rejected: &Receiver<Rejected>,
+        claims: &mut HashMap<u32, Claim>,
+    ) -> Result<Option<ByteRecord>> {
+        let mut batches: Vec<Batch> = queues.iter().map(|_| Vec::new()).collect();
+        let mut byte_record = ByteRecord::new();
+        let mut barrier = None;
+
+        while rows.read(&mut byte_record)? {
+            if spans_shards(&byte_record, &rows.columns, claims, queues.len()) {
+                barrier = Some(byte_record);
+                break;
+            }
+            self.tally.rows += 1;
+            let shard =
+                usize::from(client_of(&byte_record, &rows.columns).unwrap_or(0)) % queues.len();
+            batches[shard].push(mem::take(&mut byte_record));
+            if batches[shard].len() >= BATCH_SIZE {
+                let batch = mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE));
+                if queues[shard].send(batch).is_err() {
+                    // The shard went down, its own error tells why.
+                    return Ok(None);
+                }
+                while let Ok((raw, err)) = rejected.try_recv() {
+                    self.reject(raw, err)?;
+                }
+            }
+        }
+
+        for (queue, batch) in queues.iter().zip(batches) {
+            if !batch.is_empty() && queue.send(batch).is_err() {
+                return Ok(None);
+            }
+        }
+        Ok(barrier)
+    }
+
+    /// Spreads the current accounts and transactions over `shards` fresh
+    /// engines, by client id.
+    fn split(&self, shards: usize) -> Result<Vec<Engine>> {
+        let mut engines: Vec<Engine> = (0..shards)
+            .map(|_| Engine::with_config(self.config.clone()))
+            .collect();
+
+        for (client, cas) in self.db.clients() {
+            engines[usize::from(client) % shards]
+                .db
+                .set_client(client, cas);
+        }
+        for stored in self.db.transactions() {
+            let (tx, trx) = stored?;
+            engines[usize::from(trx.client_id()) % shards]
+                .db
+                .insert_transaction(tx, trx)?;
+        }
+        Ok(engines)
+    }
+
+    /// Brings the shards accounts and transactions back in. An account is
+    /// only taken from the shard its client belongs to. Fails with
+    /// [`DBError::TransactionAlreadyExists`] if two shards hold the same
+    /// transaction id, which the dispatch is meant to rule out.
+    fn merge(&mut self, engines: Vec<Engine>) -> Result<()> {
+        let shards = engines.len();
+        let mut merged = HashSet::new();
+        for (shard, engine) in engines.into_iter().enumerate() {
+            for (client, cas) in engine.db.clients() {
+                if usize::from(client) % shards == shard {
+                    self.db.set_client(client, cas);
+                }
+            }
+            for stored in engine.db.transactions() {
+                let (tx, trx) = stored?;
+                if !merged.insert(tx) {
+                    return Err(DBError::TransactionAlreadyExists.into());
+                }
+                self.db.put_transaction(tx, trx)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Client id of a raw row, as far as dispatching goes. A row we can't make
+/// sense of is rejected by whichever shard gets it.
+fn client_of(row: &ByteRecord, columns: &ColumnMap) -> Option<u16> {
+    columns
+        .get(row, Column::Client)
+        .and_then(|field| std::str::from_utf8(field).ok())
+        .and_then(|field| field.trim().parse().ok())
+}
+
+/// Whether a raw row can't be applied by the shard of its client alone: a
+/// transfer, a dispute, resolve or chargeback of one, or a row referring to
+/// a transaction id claimed by a client of another shard. The id of a new
+/// deposit, withdrawal or transfer is claimed on the way.
+fn spans_shards(
+    row: &ByteRecord,
+    columns: &ColumnMap,
+    claims: &mut HashMap<u32, Claim>,
+    shards: usize,
+) -> bool {
+    let field = |column| columns.get(row, column).map(<[u8]>::trim_ascii);
+    let tx = field(Column::Tx)
+        .and_then(|field| std::str::from_utf8(field).ok())
+        .and_then(|field| field.parse::<u32>().ok());
+    let kind = field(Column::Type).and_then(TransactionKind::new);
+    let (Some(tx), Some(kind), Some(client)) = (tx, kind, client_of(row, columns)) else {
+        // Rejected by whichever shard gets it.
+        return false;
+    };
+    let elsewhere =
+        |claim: &Claim| usize::from(claim.client) % shards != usize::from(client) % shards;
+
+    match kind {
+        TransactionKind::Deposit | TransactionKind::Withdrawal | TransactionKind::Transfer => {
+            let transfer = kind == TransactionKind::Transfer;
+            match claims.get(&tx) {
+                Some(claim) => transfer || elsewhere(claim),
+                None => {
+                    // The id is claimed when first seen, before any shard
+                    // validates the row, so the claim may belong to a row
+                    // rejected later on, e.g. for an unknown client or
+                    // insufficient funds. A later row reusing the id from
+                    // another shard still spans shards, and apply_spanning
+                    // looks the id up on the claim's shard rather than
+                    // trusting it was stored there, so it gets the outcome
+                    // of a sequential run either way.
+                    claims.insert(tx, Claim { client, transfer });
+                    transfer
+                }
+            }
+        }
+        _ => claims
+            .get(&tx)
+            .is_some_and(|claim| claim.transfer || elsewhere(claim)),
+    }
+}
+
+/// Applies the batches a shard receives until the input is over. Rejected
+/// rows go back to the reader, which counts them and owns the rejects log,
+/// the raw rows only being kept when it has one.
+fn run_shard(
+    mut engine: Engine,
+    batches: Receiver<Batch>,
+    rejected: Sender<Rejected>,
+    log_rejects: bool,
+    columns: ColumnMap,
+) -> Result<Engine> {
+    for batch in batches {
+        for mut byte_record in batch {
+            // Parsing trims the record in place, so we keep the raw row
+            // around when we need to report it.
+            let raw = log_rejects.then(|| byte_record.clone());
+
+            let outcome = match Record::from_columns(&mut byte_record, &columns) {
+                Ok(record) => engine.process_record(&record),
+                Err(err) => Err(err.into()),
+            };
+
+            if let Err(err) = outcome {
+                if err.is_fatal() {
+                    return Err(err);
+                }
+                // The reader only goes away on a fatal error.
+                let _ = rejected.send((raw, err));
+            }
+        }
+    }
+    Ok(engine)
+}
+
+#[test]
+fn test_merge_refuses_shared_ids() {
+    use super::error::EngineErrorKind;
+    use super::protocol::Transaction;
+
+    let shards: Vec<Engine> = [1, 2]
+        .into_iter()
+        .map(|client| {
+            let mut engine = Engine::new();
+            let trx = Transaction::new(TransactionKind::Deposit, client, "1.0".parse().unwrap());
+            engine.db.insert_transaction(7, trx).unwrap();
+            engine
+        })
+        .collect();
+    let err = Engine::new().merge(shards).unwrap_err();
+    assert!(matches!(
+        err.kind(),
+        EngineErrorKind::DBError(DBError::TransactionAlreadyExists)
+    ));
+}
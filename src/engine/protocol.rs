@@ -1,5 +1,7 @@
 //! Transaction protocol.
 
+use super::amount::Amount;
+use super::db::DBError;
 use super::record::Record;
 
 /// The kind of transaction we know how to process
@@ -22,6 +24,9 @@ pub enum TransactionKind {
     /// amount from the client's account and resulting freezing the
     /// account.
     Chargeback,
+    /// Move value from a client's account to another one's, both sides
+    /// being applied at once.
+    Transfer,
 }
 
 impl TransactionKind {
@@ -35,33 +40,81 @@ impl TransactionKind {
             b"dispute" => Some(Self::Dispute),
             b"resolve" => Some(Self::Resolve),
             b"chargeback" => Some(Self::Chargeback),
+            b"transfer" => Some(Self::Transfer),
             _ => None,
         }
     }
 }
 
-#[derive(Debug)]
+/// Where a stored transaction stands in the dispute process.
+/// A transaction can only be disputed once, and a dispute ends either
+/// resolved or charged back, both being final.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransactionState {
+    /// Applied to the client's account and not disputed.
+    Processed,
+    /// Under dispute, its amount is held.
+    Disputed,
+    /// The dispute was settled in favour of the client.
+    Resolved,
+    /// The dispute ended with the funds reversed.
+    ChargedBack,
+}
+
+impl TransactionState {
+    /// Returns the state reached by applying a transaction of `kind`, or
+    /// the reason why this transition is not allowed.
+    pub fn next(self, kind: TransactionKind) -> Result<Self, DBError> {
+        use TransactionKind::*;
+        use TransactionState::*;
+
+        match (self, kind) {
+            // Only dispute process transactions move a stored one forward.
+            (_, Deposit) | (_, Withdrawal) | (_, Transfer) => Err(DBError::OperationNotPermitted),
+            (Processed, Dispute) => Ok(Disputed),
+            (Processed, _) => Err(DBError::TransactionNotInDispute),
+            (Disputed, Resolve) => Ok(Resolved),
+            (Disputed, Chargeback) => Ok(ChargedBack),
+            (Disputed, _) => Err(DBError::TransactionAlreadyDisputed),
+            (Resolved, _) => Err(DBError::TransactionAlreadyResolved),
+            (ChargedBack, _) => Err(DBError::TransactionAlreadyChargedBack),
+        }
+    }
+}
+
+/// A deposit, withdrawal or transfer we keep track of, as a dispute may
+/// refer to it later on.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Transaction {
-    #[allow(dead_code)]
     kind: TransactionKind,
     client_id: u16,
-    amount: f32,
-    disputed: bool,
+    /// The client credited by a transfer.
+    destination: Option<u16>,
+    amount: Amount,
+    state: TransactionState,
     // We might want to refactor this with an optional value
     // to match the specs about some transaction that doesn't have
     // an amount value. But because it will not save any space we choose
     // to go for the easy way.
-    // amount: Option<f32>,
+    // amount: Option<Amount>,
 }
 
 impl Transaction {
-    #[allow(dead_code)]
-    pub fn new(kind: TransactionKind, client_id: u16, amount: f32) -> Self {
+    pub fn new(kind: TransactionKind, client_id: u16, amount: Amount) -> Self {
         Self {
             kind,
             client_id,
+            destination: None,
             amount,
-            disputed: false,
+            state: TransactionState::Processed,
+        }
+    }
+
+    /// A transfer from `client_id` to `destination`.
+    pub fn transfer(client_id: u16, destination: u16, amount: Amount) -> Self {
+        Self {
+            destination: Some(destination),
+            ..Self::new(TransactionKind::Transfer, client_id, amount)
         }
     }
 
@@ -69,12 +122,17 @@ impl Transaction {
         Self {
             kind: record.transaction_kind,
             client_id: record.client,
+            destination: record.destination,
             amount: record.amount,
-            disputed: false,
+            state: TransactionState::Processed,
         }
     }
 
-    pub fn amount(&self) -> f32 {
+    pub fn kind(&self) -> TransactionKind {
+        self.kind
+    }
+
+    pub fn amount(&self) -> Amount {
         self.amount
     }
 
@@ -82,13 +140,22 @@ impl Transaction {
         self.client_id
     }
 
+    /// The client credited, if it's a transfer.
+    pub fn destination(&self) -> Option<u16> {
+        self.destination
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
     // Set the dispute state of a transaction.
-    pub fn set_dispute(&mut self, b: bool) {
-        self.disputed = b;
+    pub fn set_state(&mut self, state: TransactionState) {
+        self.state = state;
     }
 
     pub fn is_in_dispute(&self) -> bool {
-        self.disputed
+        self.state == TransactionState::Disputed
     }
 }
 
@@ -99,6 +166,10 @@ fn test_transaction_parsing() {
         TransactionKind::new(byte_record),
         Some(TransactionKind::Withdrawal)
     );
+    assert_eq!(
+        TransactionKind::new(b"transfer"),
+        Some(TransactionKind::Transfer)
+    );
 }
 
 #[test]
@@ -108,3 +179,40 @@ fn test_bad_transaction_parsing() {
     assert_eq!(TransactionKind::new(b""), None);
     assert_eq!(TransactionKind::new(b" "), None);
 }
+
+#[test]
+fn test_transaction_state_transitions() {
+    use TransactionKind::*;
+    use TransactionState::*;
+
+    assert_eq!(Processed.next(Dispute), Ok(Disputed));
+    assert_eq!(
+        Processed.next(Resolve),
+        Err(DBError::TransactionNotInDispute)
+    );
+    assert_eq!(
+        Processed.next(Chargeback),
+        Err(DBError::TransactionNotInDispute)
+    );
+    assert_eq!(Disputed.next(Resolve), Ok(Resolved));
+    assert_eq!(Disputed.next(Chargeback), Ok(ChargedBack));
+    assert_eq!(
+        Disputed.next(Dispute),
+        Err(DBError::TransactionAlreadyDisputed)
+    );
+    for kind in [Dispute, Resolve, Chargeback] {
+        assert_eq!(
+            Resolved.next(kind),
+            Err(DBError::TransactionAlreadyResolved)
+        );
+        assert_eq!(
+            ChargedBack.next(kind),
+            Err(DBError::TransactionAlreadyChargedBack)
+        );
+    }
+    assert_eq!(Processed.next(Deposit), Err(DBError::OperationNotPermitted));
+    assert_eq!(
+        Processed.next(Transfer),
+        Err(DBError::OperationNotPermitted)
+    );
+}
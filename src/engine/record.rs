@@ -1,7 +1,9 @@
 //! Record handler. We choose to favor speed and efficient memory consumtion
-//! by using an unsafe parsing mechanism for numerical value from bytes.
-//! But it should be fine because we are protected by the type system.
+//! by parsing numerical values straight from the bytes of the row, a field
+//! that isn't valid UTF-8 being rejected like any other bad value.
 
+use super::amount::Amount;
+use super::columns::{Column, ColumnMap};
 use super::protocol::TransactionKind;
 use csv::ByteRecord;
 
@@ -16,6 +18,7 @@ use csv::ByteRecord;
 //     amount: &'a [u8],
 // }
 
+/// A single transaction row, parsed and validated.
 #[derive(Debug, PartialEq)]
 pub struct Record {
     pub transaction_kind: TransactionKind,
@@ -24,28 +27,84 @@ pub struct Record {
     // This field is not protected by the type system and so needs
     // validation during parsing because we guess a negative number
     // would be odd here
-    pub amount: f32,
+    pub amount: Amount,
+    /// The client credited, only set for transfers.
+    pub destination: Option<u16>,
 }
 
 impl Record {
-    /// Returns a [`Record`] from a [`csv::ByteRecord`].
+    pub fn new(transaction_kind: TransactionKind, client: u16, tx: u32, amount: Amount) -> Self {
+        Self {
+            transaction_kind,
+            client,
+            tx,
+            amount,
+            destination: None,
+        }
+    }
+
+    /// A transfer of `amount` from `client` to `destination`.
+    pub fn transfer(client: u16, tx: u32, destination: u16, amount: Amount) -> Self {
+        Self {
+            destination: Some(destination),
+            ..Self::new(TransactionKind::Transfer, client, tx, amount)
+        }
+    }
+
+    /// Returns a [`Record`] from a [`csv::ByteRecord`] laid out in the
+    /// canonical column order.
+    /// A dispute, resolve or chargeback may leave the trailing amount field
+    /// out.
     pub fn from_byterecord(record: &mut ByteRecord) -> Result<Self, RecordError> {
+        Self::from_columns(record, &ColumnMap::default())
+    }
+
+    /// Returns a [`Record`] from a [`csv::ByteRecord`] whose columns sit
+    /// where `columns` says. A deposit, withdrawal or transfer needs an
+    /// amount, the other kinds leaving it empty or out. The destination
+    /// field is only read for transfers, which need one.
+    pub fn from_columns(record: &mut ByteRecord, columns: &ColumnMap) -> Result<Self, RecordError> {
         record.trim();
+        let (Some(txk), Some(client), Some(tx)) = (
+            columns.get(record, Column::Type),
+            columns.get(record, Column::Client),
+            columns.get(record, Column::Tx),
+        ) else {
+            return Err(RecordError::MissingField);
+        };
         if let (Some(txk), Some(client), Some(tx), Some(amount)) = (
-            TransactionKind::new(&record[0]),
-            parse_unchecked(&record[1]),
-            parse_unchecked(&record[2]),
-            parse_unchecked_f32(&record[3]),
+            TransactionKind::new(txk),
+            parse_field(client),
+            parse_field(tx),
+            parse_amount(columns.get(record, Column::Amount).unwrap_or_default()),
         ) {
-            // Round to 4 places past the decimal if we are not sure
-            // about the input source.
-            // let amount = (amount * 1_000.0).trunc() / 1_000.0;
-
+            let moves_funds = matches!(
+                txk,
+                TransactionKind::Deposit | TransactionKind::Withdrawal | TransactionKind::Transfer
+            );
+            if moves_funds
+                && columns
+                    .get(record, Column::Amount)
+                    .unwrap_or_default()
+                    .is_empty()
+            {
+                return Err(RecordError::MissingField);
+            }
+            let destination = match txk {
+                TransactionKind::Transfer => match columns.get(record, Column::Destination) {
+                    Some(destination) if !destination.is_empty() => {
+                        Some(parse_field(destination).ok_or(RecordError::Parse)?)
+                    }
+                    _ => return Err(RecordError::MissingField),
+                },
+                _ => None,
+            };
             let record = Self {
                 transaction_kind: txk,
                 client,
                 tx,
                 amount,
+                destination,
             };
             if record.is_valid() {
                 Ok(record)
@@ -58,36 +117,27 @@ impl Record {
     }
 
     /// Checks the validity of this [`Record`].
-    /// Only checks if the amount is positive at the moment.
+    /// Checks that the amount is positive, and that a transfer goes to
+    /// someone else.
     pub fn is_valid(&self) -> bool {
-        self.amount >= 0.0
+        !self.amount.is_negative() && self.destination != Some(self.client)
     }
 }
 
-/// For performance reason we use an unsafe unchecked bytes parsing
-/// because we discard bad shaped record by leveraging protection from
-/// the type system.
-pub fn parse_unchecked<T: Sized + std::str::FromStr>(x: &[u8]) -> Option<T> {
-    if let Ok(y) = unsafe { std::str::from_utf8_unchecked(x).parse::<T>() } {
-        Some(y)
-    } else {
-        None
-    }
+/// Parses a numerical field straight from the bytes, None meaning it isn't
+/// valid UTF-8 or doesn't parse as a `T`.
+pub fn parse_field<T: std::str::FromStr>(x: &[u8]) -> Option<T> {
+    std::str::from_utf8(x).ok()?.parse().ok()
 }
 
-/// Uses an unsafe parsing for performance reason. Implemented as a separate
-/// function in order to default to float value : 0.0.
-pub fn parse_unchecked_f32(x: &[u8]) -> Option<f32> {
-    let v = unsafe {
-        std::str::from_utf8_unchecked(x)
-            .parse::<f32>()
-            .unwrap_or_default()
-    };
-
-    if v < std::f32::MIN || v > std::f32::MAX {
-        None
+/// Parses an [`Amount`] straight from the bytes. Implemented as a separate
+/// function in order to default to a zero amount when the field is empty,
+/// as it is for dispute, resolve and chargeback.
+pub fn parse_amount(x: &[u8]) -> Option<Amount> {
+    if x.is_empty() {
+        Some(Amount::ZERO)
     } else {
-        Some(v)
+        Amount::from_bytes(x)
     }
 }
 
@@ -99,6 +149,8 @@ pub enum RecordError {
     /// byte records.
     Parse,
     Invalid,
+    /// The row doesn't have enough fields.
+    MissingField,
 }
 
 #[test]
@@ -110,11 +162,43 @@ fn test_record_parsing() {
         transaction_kind: TransactionKind::Deposit,
         client: 1,
         tx: 3,
-        amount: 2.0,
+        amount: "2.0".parse().unwrap(),
+        destination: None,
     };
     assert_eq!(record, Record::from_byterecord(&mut byte_record).unwrap());
 }
 
+#[test]
+fn test_transfer_record_parsing() {
+    let mut byte_record = ByteRecord::from(vec!["transfer", "1", "3", "2.0", " 2"]);
+    assert_eq!(
+        Record::from_byterecord(&mut byte_record),
+        Ok(Record::transfer(1, 3, 2, "2.0".parse().unwrap()))
+    );
+
+    // Other kinds don't care about the destination.
+    let mut byte_record = ByteRecord::from(vec!["deposit", "1", "3", "2.0", "2"]);
+    assert_eq!(
+        Record::from_byterecord(&mut byte_record)
+            .unwrap()
+            .destination,
+        None
+    );
+
+    for (row, err) in [
+        (vec!["transfer", "1", "3", "2.0"], RecordError::MissingField),
+        (
+            vec!["transfer", "1", "3", "2.0", ""],
+            RecordError::MissingField,
+        ),
+        (vec!["transfer", "1", "3", "2.0", "bob"], RecordError::Parse),
+        (vec!["transfer", "1", "3", "2.0", "1"], RecordError::Invalid),
+    ] {
+        let mut byte_record = ByteRecord::from(row);
+        assert_eq!(Record::from_byterecord(&mut byte_record), Err(err));
+    }
+}
+
 #[test]
 fn test_parsing_bad_record_transaction() {
     let csv_row = vec!["rule the world", "  xxx", "3", "2.0"];
@@ -140,9 +224,8 @@ fn test_record_is_valid() {
 }
 
 #[test]
-fn test_record_disallow_neg_infinity() {
-    // A -Inf parsing result should occur with value '-3.5e38'
-    // and Inf with '3.5e38'.
+fn test_record_rejects_negative_exponent_amount() {
+    // Amounts are plain decimals, so a float beyond any range isn't one.
     let csv_row = vec!["withdrawal", "  7", "3", "-3.5e38"];
     let mut byte_record = ByteRecord::from(csv_row);
 
@@ -150,10 +233,90 @@ fn test_record_disallow_neg_infinity() {
 }
 
 #[test]
-fn test_record_disallow_infinity() {
-    // A Inf parsing result should occur with value '3.5e38'.
+fn test_record_rejects_exponent_amount() {
     let csv_row = vec!["deposit", "  7", "3", "3.5e38"];
     let mut byte_record = ByteRecord::from(csv_row);
 
     assert!(Record::from_byterecord(&mut byte_record).is_err());
 }
+
+#[test]
+fn test_record_exact_amount() {
+    let csv_row = vec!["deposit", "1", "3", "0.1234"];
+    let mut byte_record = ByteRecord::from(csv_row);
+
+    let record = Record::from_byterecord(&mut byte_record).unwrap();
+    assert_eq!(record.amount, Amount::from_raw(1234));
+}
+
+#[test]
+fn test_record_empty_amount() {
+    let csv_row = vec!["dispute", "1", "3", ""];
+    let mut byte_record = ByteRecord::from(csv_row);
+
+    let record = Record::from_byterecord(&mut byte_record).unwrap();
+    assert_eq!(record.amount, Amount::ZERO);
+}
+
+#[test]
+fn test_record_too_precise_amount() {
+    let csv_row = vec!["deposit", "1", "3", "1.00005"];
+    let mut byte_record = ByteRecord::from(csv_row);
+
+    assert_eq!(
+        Record::from_byterecord(&mut byte_record),
+        Err(RecordError::Parse)
+    );
+}
+
+#[test]
+fn test_record_missing_fields() {
+    let mut byte_record = ByteRecord::from(vec!["deposit", "1"]);
+    assert_eq!(
+        Record::from_byterecord(&mut byte_record),
+        Err(RecordError::MissingField)
+    );
+
+    // Dispute rows without the trailing comma are fine.
+    let mut byte_record = ByteRecord::from(vec!["dispute", "1", "3"]);
+    let record = Record::from_byterecord(&mut byte_record).unwrap();
+    assert_eq!(record.amount, Amount::ZERO);
+
+    // Rows moving funds need an amount, empty or left out.
+    for row in [
+        vec!["deposit", "1", "3", ""],
+        vec!["withdrawal", "1", "3", "  "],
+        vec!["withdrawal", "1", "3"],
+        vec!["transfer", "1", "3", "", "2"],
+    ] {
+        let mut byte_record = ByteRecord::from(row);
+        assert_eq!(
+            Record::from_byterecord(&mut byte_record),
+            Err(RecordError::MissingField)
+        );
+    }
+}
+
+#[test]
+fn test_record_from_columns() {
+    let headers = ByteRecord::from(vec!["tx", "client", "note", "type", "amount"]);
+    let columns = ColumnMap::from_headers(&headers, &[]).unwrap();
+
+    let mut byte_record = ByteRecord::from(vec!["3", "1", "whatever", "deposit", "2.0"]);
+    assert_eq!(
+        Record::from_columns(&mut byte_record, &columns),
+        Ok(Record::new(
+            TransactionKind::Deposit,
+            1,
+            3,
+            "2.0".parse().unwrap()
+        ))
+    );
+
+    // The type comes after the row ends.
+    let mut byte_record = ByteRecord::from(vec!["3", "1", "whatever"]);
+    assert_eq!(
+        Record::from_columns(&mut byte_record, &columns),
+        Err(RecordError::MissingField)
+    );
+}
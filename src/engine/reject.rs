@@ -0,0 +1,208 @@
+//! Rejected records log. Every row we fail to parse or to apply is reported
+//! with its position in the input, the raw row and the reason why, so the
+//! resulting balances can be audited against the source file. When a run
+//! reads several inputs, every entry also names the one it comes from.
+
+use super::error::{EngineError, EngineErrorKind, Result};
+use csv::ByteRecord;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Columns of the csv flavour of the log.
+const CSV_HEADER: [&str; 5] = ["line", "byte", "kind", "reason", "row"];
+/// Column naming the input of an entry, ahead of the others.
+const CSV_INPUT: &str = "input";
+
+/// Supported formats for the rejected records log.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RejectFormat {
+    /// One csv row per rejected record, preceded by a header.
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+impl RejectFormat {
+    /// Guesses the format from a file extension, defaulting to csv.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") | Some("json") | Some("ndjson") => Self::Jsonl,
+            _ => Self::Csv,
+        }
+    }
+}
+
+enum RejectWriter {
+    Csv(Box<csv::Writer<Box<dyn Write + Send>>>),
+    Jsonl(io::BufWriter<Box<dyn Write + Send>>),
+}
+
+/// Writes down every record the engine discards.
+pub struct RejectLog {
+    writer: RejectWriter,
+    /// Whether the csv header is still to be written. It waits for the
+    /// first entry, by then the log knows whether entries name their input.
+    pending_header: bool,
+    /// The input the next entries come from, once they name it.
+    input: Option<String>,
+}
+
+impl RejectLog {
+    pub fn new(writer: Box<dyn Write + Send>, format: RejectFormat) -> Result<Self> {
+        let writer = match format {
+            RejectFormat::Csv => RejectWriter::Csv(Box::new(csv::Writer::from_writer(writer))),
+            RejectFormat::Jsonl => RejectWriter::Jsonl(io::BufWriter::new(writer)),
+        };
+        Ok(Self {
+            pending_header: format == RejectFormat::Csv,
+            writer,
+            input: None,
+        })
+    }
+
+    /// Creates a log file at `path`, its format guessed from the extension.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::create(path.as_ref())?;
+        Self::new(Box::new(file), RejectFormat::from_path(path))
+    }
+
+    /// Names `path` as the input of the entries logged from now on. The
+    /// columns of a csv log are set by its header, so it only names inputs
+    /// if told to before its first entry.
+    pub(crate) fn set_input(&mut self, path: &Path) {
+        if self.pending_header
+            || self.input.is_some()
+            || matches!(self.writer, RejectWriter::Jsonl(_))
+        {
+            self.input = Some(path.display().to_string());
+        }
+    }
+
+    /// Reports a rejected `row` along with the error explaining why.
+    pub fn log(&mut self, row: &ByteRecord, err: &EngineError) -> Result<()> {
+        self.write_header()?;
+        let (line, byte) = row
+            .position()
+            .map_or((0, 0), |pos| (pos.line(), pos.byte()));
+        let (kind, reason) = describe(err);
+        let raw = join(row);
+
+        match self.writer {
+            RejectWriter::Csv(ref mut wtr) => {
+                if let Some(input) = &self.input {
+                    wtr.write_field(input)?;
+                }
+                wtr.write_record([
+                    line.to_string().as_bytes(),
+                    byte.to_string().as_bytes(),
+                    kind.as_bytes(),
+                    reason.as_bytes(),
+                    &raw,
+                ])?;
+            }
+            RejectWriter::Jsonl(ref mut wtr) => {
+                wtr.write_all(b"{")?;
+                if let Some(input) = &self.input {
+                    wtr.write_all(b"\"input\":")?;
+                    write_json_str(wtr, input.as_bytes())?;
+                    wtr.write_all(b",")?;
+                }
+                write!(
+                    wtr,
+                    "\"line\":{},\"byte\":{},\"kind\":\"{}\",\"reason\":",
+                    line, byte, kind
+                )?;
+                write_json_str(wtr, reason.as_bytes())?;
+                wtr.write_all(b",\"row\":")?;
+                write_json_str(wtr, &raw)?;
+                wtr.write_all(b"}\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_header()?;
+        match self.writer {
+            RejectWriter::Csv(ref mut wtr) => wtr.flush()?,
+            RejectWriter::Jsonl(ref mut wtr) => wtr.flush()?,
+        }
+        Ok(())
+    }
+
+    /// Writes the csv header, unless it's written already.
+    fn write_header(&mut self) -> Result<()> {
+        if let (true, RejectWriter::Csv(ref mut wtr)) = (self.pending_header, &mut self.writer) {
+            if self.input.is_some() {
+                wtr.write_field(CSV_INPUT)?;
+            }
+            wtr.write_record(CSV_HEADER)?;
+            self.pending_header = false;
+        }
+        Ok(())
+    }
+}
+
+/// Returns which layer refused the record and the concrete reason.
+pub(crate) fn describe(err: &EngineError) -> (&'static str, String) {
+    match err.kind() {
+        EngineErrorKind::RecordError(e) => ("record", format!("{:?}", e)),
+        EngineErrorKind::DBError(e) => ("db", format!("{:?}", e)),
+        other => ("engine", err_name(other)),
+    }
+}
+
+/// Name of an [`EngineErrorKind`] variant, without its payload.
+fn err_name(kind: &EngineErrorKind) -> String {
+    let name = format!("{:?}", kind);
+    match name.find('(') {
+        Some(i) => name[..i].to_string(),
+        None => name,
+    }
+}
+
+/// The row as it was in the input, fields joined back with commas.
+fn join(row: &ByteRecord) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(row.as_slice().len() + row.len());
+    for (i, field) in row.iter().enumerate() {
+        if i > 0 {
+            raw.push(b',');
+        }
+        raw.extend_from_slice(field);
+    }
+    raw
+}
+
+/// Writes `s` as a JSON string literal, escaping what needs to be.
+fn write_json_str<W: Write>(w: &mut W, s: &[u8]) -> io::Result<()> {
+    w.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => w.write_all(b"\\\"")?,
+            '\\' => w.write_all(b"\\\\")?,
+            '\n' => w.write_all(b"\\n")?,
+            '\r' => w.write_all(b"\\r")?,
+            '\t' => w.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+            c => write!(w, "{}", c)?,
+        }
+    }
+    w.write_all(b"\"")
+}
+
+#[test]
+fn test_reject_format_from_path() {
+    assert_eq!(
+        RejectFormat::from_path("rejects.jsonl"),
+        RejectFormat::Jsonl
+    );
+    assert_eq!(RejectFormat::from_path("rejects.csv"), RejectFormat::Csv);
+    assert_eq!(RejectFormat::from_path("rejects"), RejectFormat::Csv);
+}
+
+#[test]
+fn test_write_json_str() {
+    let mut out = Vec::new();
+    write_json_str(&mut out, b"say \"hi\"\\\n").unwrap();
+    assert_eq!(out, br#""say \"hi\"\\\n""#);
+}
@@ -0,0 +1,299 @@
+//! TCP server mode. Partners stream their transactions over plain TCP
+//! connections, all applied to one shared [`Engine`], and may query the
+//! accounts on the way.
+//!
+//! The protocol is line based, each line being a csv row answered by one
+//! line, in order:
+//! - a transaction row, e.g. `deposit,1,1,10.0`, is applied and answered by
+//!   `ok`, or by `error,<kind>,<reason>` if it's rejected, `kind` and
+//!   `reason` being the ones of the rejects log;
+//! - a header row, e.g. `type,client,tx,amount`, tells where the columns
+//!   are in the rows that follow it, the canonical order being assumed
+//!   until then, and is answered by `ok`;
+//! - `account,<client>` is answered by the client's account, as a row of
+//!   the csv report, or by `error,db,ClientNotFound`;
+//! - `accounts` is answered by every account, one per line, followed by
+//!   `end`.
+//!
+//! The rows of a connection are applied in the order they are received.
+//! Rows of a given client coming from several connections are applied in
+//! the order they reach the engine. Rejected rows are also reported to the
+//! engine's rejects log, if it keeps one, their position being the one in
+//! their connection's stream.
+
+use super::columns::{Column, ColumnMap};
+use super::db::client::ClientAccountState;
+use super::error::{EngineError, Result};
+use super::protocol::TransactionKind;
+use super::reject::describe;
+use super::Engine;
+use csv::ByteRecord;
+use std::io::{self, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// Serves `engine` to every connection `listener` accepts, each one on its
+/// own thread. Only returns if accepting connections fails.
+pub fn serve(listener: TcpListener, engine: Engine) -> io::Result<()> {
+    serve_shared(listener, Arc::new(Mutex::new(engine)))
+}
+
+/// Same as [`serve`], the engine remaining reachable by the caller.
+pub fn serve_shared(listener: TcpListener, engine: Arc<Mutex<Engine>>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || {
+            // A connection going away only concerns its own partner.
+            let _ = handle_connection(stream, &engine);
+        });
+    }
+    Ok(())
+}
+
+/// Answers every line of a connection until it's closed, or until the
+/// engine fails for good.
+fn handle_connection(stream: TcpStream, engine: &Mutex<Engine>) -> Result<()> {
+    let mut out = BufWriter::new(stream.try_clone()?);
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(stream);
+    let mut row = ByteRecord::new();
+    let mut columns = ColumnMap::default();
+
+    loop {
+        match rdr.read_byte_record(&mut row) {
+            Ok(true) => answer(&mut row, engine, &mut columns, &mut out)?,
+            Ok(false) => break,
+            Err(err) => {
+                write_error(&mut out, &err.into())?;
+                out.flush()?;
+                break;
+            }
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Answers a single line, `columns` telling where the columns of the
+/// connection's rows are.
+fn answer<W: Write>(
+    row: &mut ByteRecord,
+    engine: &Mutex<Engine>,
+    columns: &mut ColumnMap,
+    out: &mut W,
+) -> Result<()> {
+    match trimmed(row.get(0)) {
+        b"account" => {
+            let client = row
+                .get(1)
+                .and_then(|field| std::str::from_utf8(field).ok())
+                .and_then(|field| field.trim().parse::<u16>().ok());
+            match client.and_then(|client| lock(engine).account(client)) {
+                Some(cas) => write_account(out, client.unwrap_or_default(), &cas)?,
+                None => writeln!(out, "error,db,ClientNotFound")?,
+            }
+        }
+        b"accounts" => {
+            // Copied out so the engine isn't held while writing.
+            let accounts: Vec<_> = lock(engine).accounts().collect();
+            for (client, cas) in accounts.iter() {
+                write_account(out, *client, cas)?;
+            }
+            writeln!(out, "end")?
+        }
+        _ => {
+            let mut engine = lock(engine);
+            // Not a transaction, so possibly a header row.
+            if TransactionKind::new(trimmed(columns.get(row, Column::Type))).is_none() {
+                if let Ok(headers) = ColumnMap::from_headers(row, &engine.config.header_aliases) {
+                    *columns = headers;
+                    writeln!(out, "ok")?;
+                    return Ok(());
+                }
+            }
+
+            match engine.submit_row(row, columns) {
+                Ok(_) => writeln!(out, "ok")?,
+                Err(err) => {
+                    write_error(out, &err)?;
+                    if err.is_fatal() {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A field without its surrounding whitespaces.
+fn trimmed(field: Option<&[u8]>) -> &[u8] {
+    field
+        .and_then(|field| std::str::from_utf8(field).ok())
+        .map_or(b"", |field| field.trim().as_bytes())
+}
+
+/// Locks the engine. A connection thread panicking doesn't take the
+/// others down: every record is applied as a whole or not at all.
+pub(crate) fn lock(engine: &Mutex<Engine>) -> std::sync::MutexGuard<'_, Engine> {
+    engine.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write_account<W: Write>(out: &mut W, client: u16, cas: &ClientAccountState) -> io::Result<()> {
+    writeln!(
+        out,
+        "{},{},{},{},{}",
+        client,
+        cas.available(),
+        cas.held(),
+        cas.total(),
+        cas.locked()
+    )
+}
+
+fn write_error<W: Write>(out: &mut W, err: &EngineError) -> io::Result<()> {
+    let (kind, reason) = describe(err);
+    writeln!(out, "error,{},{}", kind, reason)
+}
+
+#[cfg(test)]
+fn spawn_server(engine: Engine) -> (std::net::SocketAddr, Arc<Mutex<Engine>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let engine = Arc::new(Mutex::new(engine));
+    let shared = Arc::clone(&engine);
+    thread::spawn(move || serve_shared(listener, shared));
+    (addr, engine)
+}
+
+#[cfg(test)]
+fn exchange<L: AsRef<[u8]>>(addr: std::net::SocketAddr, lines: L) -> Vec<String> {
+    use std::io::{BufRead, BufReader};
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(lines.as_ref()).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    BufReader::new(stream)
+        .lines()
+        .map(|line| line.unwrap())
+        .collect()
+}
+
+#[test]
+fn test_server_answers() {
+    let (addr, engine) = spawn_server(Engine::new());
+
+    let replies = exchange(
+        addr,
+        "type,client,tx,amount\n\
+         deposit,1,1,10.0\n\
+         withdrawal,1,2,20.0\n\
+         teleport,1,3,1.0\n\
+         dispute, 1, 1,\n\
+         account,1\n\
+         account,2\n\
+         accounts\n",
+    );
+    assert_eq!(
+        replies,
+        vec![
+            "ok",
+            "ok",
+            "error,db,NotEnoughAvailableCredit",
+            "error,record,Parse",
+            "ok",
+            "1,0.0000,10.0000,10.0000,false",
+            "error,db,ClientNotFound",
+            "1,0.0000,10.0000,10.0000,false",
+            "end",
+        ]
+    );
+
+    // Counted as the rows of an input are, header row aside.
+    assert_eq!(lock(&engine).tally.rows, 4);
+    assert_eq!(lock(&engine).tally.rejected, 2);
+}
+
+#[test]
+fn test_server_invalid_utf8() {
+    let (addr, _) = spawn_server(Engine::new());
+
+    let replies = exchange(
+        addr,
+        b"deposit,\xff\xfe,1,10.0\n\
+          deposit,1,\xc3,10.0\n\
+          transfer,1,2,1.0,\x80\n\
+          account,1\n",
+    );
+    assert_eq!(
+        replies,
+        vec![
+            "error,record,Parse",
+            "error,record,Parse",
+            "error,record,Parse",
+            "error,db,ClientNotFound",
+        ]
+    );
+}
+
+#[test]
+fn test_server_reordered_headers() {
+    let (addr, _) = spawn_server(Engine::new());
+
+    let replies = exchange(
+        addr,
+        "deposit,1,1,10.0\n\
+         tx,Client,type,amount,note\n\
+         2,1,withdrawal,4.0,atm\n\
+         deposit,1,3,1.0\n\
+         account,1\n",
+    );
+    assert_eq!(
+        replies,
+        vec![
+            "ok",
+            "ok",
+            "ok",
+            "error,record,Parse",
+            "1,6.0000,0.0000,6.0000,false",
+        ]
+    );
+}
+
+#[test]
+fn test_server_concurrent_partners() {
+    let (addr, engine) = spawn_server(Engine::new());
+
+    // Every partner owns its clients, and withdraws what it deposited
+    // right before: any reordering would be refused for lack of funds.
+    let partners: Vec<_> = (0..8u32)
+        .map(|partner| {
+            thread::spawn(move || {
+                let mut lines = String::new();
+                for i in 0..200u32 {
+                    let client = partner * 10 + i % 10;
+                    let tx = partner * 1_000 + i * 2;
+                    lines.push_str(&format!("deposit,{},{},1.5\n", client, tx));
+                    lines.push_str(&format!("withdrawal,{},{},1.5\n", client, tx + 1));
+                }
+                exchange(addr, &lines)
+            })
+        })
+        .collect();
+
+    for partner in partners {
+        let replies = partner.join().unwrap();
+        assert_eq!(replies.len(), 400);
+        assert!(replies.iter().all(|reply| reply == "ok"));
+    }
+
+    let engine = lock(&engine);
+    assert_eq!(engine.accounts().count(), 80);
+    assert!(engine
+        .accounts()
+        .all(|(_, cas)| cas.total() == super::amount::Amount::ZERO));
+}
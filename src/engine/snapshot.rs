@@ -0,0 +1,215 @@
+//! Binary snapshot of the databases, so a run can pick up where the previous
+//! one left off.
+//!
+//! Integers are little endian. A snapshot starts with the `KCTS` magic and
+//! the format version on two bytes, followed by:
+//! - its generation on eight bytes, bumped by every snapshot saved over the
+//!   previous one, which the journal refers to;
+//! - the number of accounts on four bytes, then each account: client id (2),
+//!   available (8), held (8), total (8) and locked (1), amounts counting
+//!   ten-thousandths of a unit;
+//! - each stored transaction: its id (4) followed by its slot (16), laid out
+//!   as in the [`DiskStorage`] index. An all zero entry ends the list.
+//!
+//! [`DiskStorage`]: super::db::DiskStorage
+
+use super::amount::Amount;
+use super::db::{
+    client::ClientAccountState, decode_transaction, encode_transaction, Storage, SLOT_SIZE,
+};
+use super::error::{EngineError, EngineErrorKind, Result};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+const MAGIC: [u8; 4] = *b"KCTS";
+/// Bumped whenever the layout changes.
+const VERSION: u16 = 2;
+/// The version before generations, still read as the first one.
+const VERSION_1: u16 = 1;
+/// Size of an account entry.
+const ACCOUNT_SIZE: usize = 27;
+/// Size of a transaction entry.
+const TRANSACTION_SIZE: usize = 4 + SLOT_SIZE;
+
+/// Writes every account and transaction of `db` to `wtr`, as the snapshot
+/// of the given `generation`.
+pub fn write<W: Write>(db: &dyn Storage, generation: u64, wtr: W) -> Result<()> {
+    let mut wtr = BufWriter::new(wtr);
+    wtr.write_all(&MAGIC)?;
+    wtr.write_all(&VERSION.to_le_bytes())?;
+    wtr.write_all(&generation.to_le_bytes())?;
+
+    wtr.write_all(&(db.clients().count() as u32).to_le_bytes())?;
+    for (client, cas) in db.clients() {
+        let mut entry = [0u8; ACCOUNT_SIZE];
+        entry[0..2].copy_from_slice(&client.to_le_bytes());
+        entry[2..10].copy_from_slice(&cas.available().raw().to_le_bytes());
+        entry[10..18].copy_from_slice(&cas.held().raw().to_le_bytes());
+        entry[18..26].copy_from_slice(&cas.total().raw().to_le_bytes());
+        entry[26] = cas.locked() as u8;
+        wtr.write_all(&entry)?;
+    }
+
+    for stored in db.transactions() {
+        let (tx, trx) = stored?;
+        wtr.write_all(&tx.to_le_bytes())?;
+        wtr.write_all(&encode_transaction(&trx))?;
+    }
+    wtr.write_all(&[0u8; TRANSACTION_SIZE])?;
+
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Loads a snapshot written by [`write`] from `rdr` into `db`, and returns
+/// its generation.
+pub fn read<R: Read>(rdr: R, db: &mut dyn Storage) -> Result<u64> {
+    let mut rdr = BufReader::new(rdr);
+    if read_bytes::<4, _>(&mut rdr)? != MAGIC {
+        return Err(invalid());
+    }
+    let generation = match u16::from_le_bytes(read_bytes(&mut rdr)?) {
+        VERSION => u64::from_le_bytes(read_bytes(&mut rdr)?),
+        VERSION_1 => 0,
+        _ => return Err(invalid()),
+    };
+
+    let accounts = u32::from_le_bytes(read_bytes(&mut rdr)?);
+    for _ in 0..accounts {
+        let entry: [u8; ACCOUNT_SIZE] = read_bytes(&mut rdr)?;
+        let client = u16::from_le_bytes([entry[0], entry[1]]);
+        let cas = ClientAccountState::from_parts(
+            amount(&entry[2..10]),
+            amount(&entry[10..18]),
+            amount(&entry[18..26]),
+            match entry[26] {
+                0 => false,
+                1 => true,
+                _ => return Err(invalid()),
+            },
+        );
+        db.set_client(client, cas);
+    }
+
+    loop {
+        let entry: [u8; TRANSACTION_SIZE] = read_bytes(&mut rdr)?;
+        let tx = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        let mut slot = [0u8; SLOT_SIZE];
+        slot.copy_from_slice(&entry[4..]);
+
+        match decode_transaction(&slot).map_err(|_| invalid())? {
+            Some(trx) => db.insert_transaction(tx, trx)?,
+            None if tx == 0 => break,
+            None => return Err(invalid()),
+        }
+    }
+
+    // Anything past the end of the list means we misread the file.
+    if rdr.read(&mut [0u8; 1])? != 0 {
+        return Err(invalid());
+    }
+    Ok(generation)
+}
+
+/// Reads exactly `N` bytes, a snapshot ending early being invalid.
+fn read_bytes<const N: usize, R: Read>(rdr: &mut R) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    match rdr.read_exact(&mut buf) {
+        Ok(()) => Ok(buf),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(invalid()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn amount(bytes: &[u8]) -> Amount {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(bytes);
+    Amount::from_raw(i64::from_le_bytes(raw))
+}
+
+fn invalid() -> EngineError {
+    EngineError::new(EngineErrorKind::InvalidSnapshot)
+}
+
+#[cfg(test)]
+fn mock_db() -> super::db::DB {
+    use super::protocol::{Transaction, TransactionKind, TransactionState};
+
+    let mut db = super::db::DB::new();
+    let mut cas = ClientAccountState::new();
+    cas.add("10.5".parse().unwrap()).unwrap();
+    cas.hold("2.5".parse().unwrap()).unwrap();
+    db.set_client(1, cas);
+    let mut cas = ClientAccountState::new();
+    cas.lock();
+    db.set_client(2, cas);
+
+    let mut trx = Transaction::new(TransactionKind::Deposit, 1, "2.5".parse().unwrap());
+    trx.set_state(TransactionState::Disputed);
+    db.insert_transaction(0, trx).unwrap();
+    let trx = Transaction::new(TransactionKind::Withdrawal, 2, "1.0".parse().unwrap());
+    db.insert_transaction(u32::MAX, trx).unwrap();
+    let trx = Transaction::transfer(1, 2, "0.5".parse().unwrap());
+    db.insert_transaction(7, trx).unwrap();
+    db
+}
+
+#[test]
+fn test_snapshot_roundtrip() {
+    use super::db::DB;
+    use super::protocol::TransactionState;
+
+    let db = mock_db();
+    let mut buf = Vec::new();
+    write(&db, 3, &mut buf).unwrap();
+
+    let mut back = DB::new();
+    assert_eq!(read(buf.as_slice(), &mut back).unwrap(), 3);
+
+    assert_eq!(
+        back.clients().collect::<Vec<_>>(),
+        db.clients().collect::<Vec<_>>()
+    );
+    let trx = back.transaction(0).unwrap().unwrap();
+    assert_eq!(trx.state(), TransactionState::Disputed);
+    assert_eq!(trx.amount(), "2.5".parse().unwrap());
+    let trx = back.transaction(u32::MAX).unwrap().unwrap();
+    assert_eq!(trx.client_id(), 2);
+    assert_eq!(back.transaction(7).unwrap().unwrap().destination(), Some(2));
+    assert_eq!(back.transactions().count(), 3);
+}
+
+#[test]
+fn test_snapshot_invalid() {
+    let mut buf = Vec::new();
+    write(&mock_db(), 0, &mut buf).unwrap();
+
+    let is_invalid = |bytes: &[u8]| {
+        let err = read(bytes, &mut super::db::DB::new()).unwrap_err();
+        matches!(err.kind(), EngineErrorKind::InvalidSnapshot)
+    };
+
+    // Truncated.
+    assert!(is_invalid(&buf[..buf.len() - 1]));
+    // Trailing garbage.
+    assert!(is_invalid(&[buf.as_slice(), b"x"].concat()));
+    // Unknown version.
+    let mut future = buf.clone();
+    future[4] = 3;
+    assert!(is_invalid(&future));
+    // Not a snapshot at all.
+    assert!(is_invalid(b"type,client,tx,amount\n"));
+}
+
+#[test]
+fn test_snapshot_version_1() {
+    let mut buf = Vec::new();
+    write(&mock_db(), 5, &mut buf).unwrap();
+
+    // The same snapshot, as written before generations.
+    let mut old = buf[..4].to_vec();
+    old.extend_from_slice(&VERSION_1.to_le_bytes());
+    old.extend_from_slice(&buf[14..]);
+    let mut db = super::db::DB::new();
+    assert_eq!(read(old.as_slice(), &mut db).unwrap(), 0);
+    assert_eq!(db.transactions().count(), 3);
+}
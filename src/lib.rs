@@ -0,0 +1,53 @@
+//! Transaction processing engine.
+//!
+//! Feeds on csv transaction records (deposit, withdrawal, transfer,
+//! dispute, resolve and chargeback) and keeps the state of every client's account up to
+//! date. The same [`Engine`] backs the command line tool and can be linked
+//! by any service needing it.
+//!
+//! ```
+//! use k_coding_test::{Amount, Engine, Record, TransactionKind, TransactionState};
+//!
+//! let mut engine = Engine::new();
+//!
+//! // From a csv stream...
+//! let csv = "type,client,tx,amount\ndeposit,1,1,10.0\n";
+//! engine.process_reader(csv.as_bytes()).unwrap();
+//!
+//! // ...or one record at a time.
+//! let amount: Amount = "2.5".parse().unwrap();
+//! let record = Record::new(TransactionKind::Withdrawal, 1, 2, amount);
+//! engine.process_record(&record).unwrap();
+//!
+//! let account = engine.account(1).unwrap();
+//! assert_eq!(account.available().to_string(), "7.5000");
+//! assert_eq!(engine.accounts().count(), 1);
+//!
+//! let deposit = engine.transaction(1).unwrap().unwrap();
+//! assert_eq!(deposit.state(), TransactionState::Processed);
+//! ```
+//!
+//! The accounts and transactions live in a [`Storage`] backend, the in-memory
+//! [`DB`] by default. Another one can be handed over with
+//! [`Engine::with_storage`], like [`DiskStorage`] which keeps the
+//! transactions on disk when they don't fit in memory. The whole state can
+//! be saved with [`Engine::save_state`] and resumed later on with
+//! [`Engine::load_state`]. In between, a [`Journal`] lets a run that died
+//! halfway through pick up right after the last row it applied.
+//!
+//! The engine can also be served over TCP with [`serve`], many partners
+//! streaming their transactions to it at once, or over HTTP with
+//! `serve_http` when built with the `http` feature.
+
+mod engine;
+
+pub use engine::{
+    decompress, expand_inputs, open_input, serve, serve_shared, Amount, ClientAccountState,
+    ClientCreation, Column, ColumnMap, Config, CreditLimits, DBError, DiskStorage, DuplicatePolicy,
+    Engine, EngineError, EngineErrorKind, FrozenPolicy, HeaderRow, InputSummary, Journal,
+    OutputFormat, OutputOptions, Quoting, Record, RecordError, RejectFormat, RejectLog, Result,
+    Storage, Transaction, TransactionKind, TransactionState, WithdrawalDisputePolicy, DB,
+    DEFAULT_MEMORY_BUDGET, DEFAULT_SYNC_EVERY,
+};
+#[cfg(feature = "http")]
+pub use engine::{serve_http, serve_http_shared};
@@ -1,37 +1,427 @@
-mod engine;
-use engine::Engine;
+use k_coding_test::{
+    decompress, expand_inputs, serve, Amount, ClientCreation, Column, Config, DiskStorage,
+    DuplicatePolicy, Engine, EngineError, FrozenPolicy, HeaderRow, Journal, OutputFormat,
+    OutputOptions, Quoting, RejectLog, WithdrawalDisputePolicy, DEFAULT_MEMORY_BUDGET,
+    DEFAULT_SYNC_EVERY,
+};
 use std::env;
+use std::io;
+use std::net::TcpListener;
+use std::path::Path;
+
+/// Serves the engine over HTTP, see `k_coding_test::serve_http`.
+#[cfg(feature = "http")]
+fn serve_http(listener: TcpListener, engine: Engine) -> io::Result<()> {
+    k_coding_test::serve_http(listener, engine)
+}
+
+#[cfg(not(feature = "http"))]
+fn serve_http(_listener: TcpListener, _engine: Engine) -> io::Result<()> {
+    unreachable!("--http is refused when parsing the command line")
+}
+
+/// Path argument meaning "read the transactions from the standard input".
+const STDIN_PATH: &str = "-";
+
+/// Address the server listens on when none is given.
+const DEFAULT_LISTEN: &str = "127.0.0.1:7878";
+
+const USAGE: &str = "Usage: k-coding-test [--rejects <rejects.csv|rejects.jsonl>] \
+     [--frozen-policy <reject-all|deposits|disputes>] \
+     [--withdrawal-disputes <refuse|credit>] [--duplicates <reject|ignore>] \
+     [--credit-limits <clients.csv>] [--default-credit-limit <amount>] \
+     [--create-clients <never|deposits|always>] \
+     [--header-alias <alias>=<column>]... \
+     [--no-headers] [--format <csv|json|jsonl>] [--delimiter <char>] \
+     [--quote <necessary|always|never|non-numeric>] [--legacy-output] \
+     [--tx-store <index file>] [--tx-memory-budget <MiB>] \
+     [--state <snapshot> [--journal <journal>]] [--threads <n>] [--merge-by <column>] \
+     <transactions.csv|directory|glob>... | -
+       k-coding-test serve [--http] [--listen <addr>] [--rejects <rejects.csv|rejects.jsonl>] \
+     [--frozen-policy <reject-all|deposits|disputes>] \
+     [--withdrawal-disputes <refuse|credit>] [--duplicates <reject|ignore>] \
+     [--credit-limits <clients.csv>] [--default-credit-limit <amount>] \
+     [--create-clients <never|deposits|always>] \
+     [--header-alias <alias>=<column>]... \
+     [--tx-store <index file>] [--tx-memory-budget <MiB>] [--state <snapshot>]";
+
+/// A mebibyte, the unit of the memory budget on the command line.
+const MIB: usize = 1024 * 1024;
+
+/// What we were asked to do.
+enum Command {
+    /// Process the transaction files, directories or globs, or the
+    /// standard input for '-', and report the accounts.
+    Process(Vec<String>),
+    /// Serve the engine over TCP on this address, speaking HTTP if asked.
+    Serve { addr: String, http: bool },
+}
+
+/// Command line options.
+struct Args {
+    command: Command,
+    /// Where to report the rejected records, if anywhere.
+    rejects: Option<String>,
+    /// Client configuration file holding their credit limits, if any.
+    credit_limits: Option<String>,
+    /// Engine settings.
+    config: Config,
+    /// Layout of the client account report.
+    output: OutputOptions,
+    /// Where to keep the transactions on disk, if they don't live in memory.
+    tx_store: Option<String>,
+    /// Memory the disk backed transaction store may use, in bytes.
+    tx_memory_budget: usize,
+    /// Snapshot to resume from, if it exists, and to save to once done.
+    state: Option<String>,
+    /// Journal of the changes applied since the snapshot, if any.
+    journal: Option<String>,
+    /// How many threads process the rows.
+    threads: usize,
+    /// Column to merge several files on, instead of taking them in turn.
+    merge_by: Option<String>,
+}
+
+impl Args {
+    /// Parses the command line, exiting with a message on misuse.
+    fn parse() -> Self {
+        let mut inputs = Vec::new();
+        let mut merge_by = None;
+        let mut listen = None;
+        let mut http = false;
+        let mut rejects = None;
+        let mut credit_limits = None;
+        let mut config = Config::default();
+        let mut output = OutputOptions::default();
+        let mut tx_store = None;
+        let mut tx_memory_budget = DEFAULT_MEMORY_BUDGET;
+        let mut state = None;
+        let mut journal = None;
+        let mut threads = 1;
+
+        let mut args = env::args().skip(1).peekable();
+        let serve = args.next_if(|arg| arg == "serve").is_some();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--rejects" => {
+                    rejects = Some(flag_value(&mut args, &arg, "a file path", |s| {
+                        Some(s.to_string())
+                    }))
+                }
+                "--frozen-policy" => {
+                    config.frozen_policy = flag_value(
+                        &mut args,
+                        &arg,
+                        "one of reject-all, deposits or disputes",
+                        FrozenPolicy::new,
+                    )
+                }
+                "--withdrawal-disputes" => {
+                    config.withdrawal_disputes = flag_value(
+                        &mut args,
+                        &arg,
+                        "refuse or credit",
+                        WithdrawalDisputePolicy::new,
+                    )
+                }
+                "--duplicates" => {
+                    config.duplicates =
+                        flag_value(&mut args, &arg, "reject or ignore", DuplicatePolicy::new)
+                }
+                "--create-clients" => {
+                    config.client_creation = flag_value(
+                        &mut args,
+                        &arg,
+                        "one of never, deposits or always",
+                        ClientCreation::new,
+                    )
+                }
+                "--credit-limits" => {
+                    credit_limits = Some(flag_value(&mut args, &arg, "a file path", |s| {
+                        Some(s.to_string())
+                    }))
+                }
+                "--default-credit-limit" => {
+                    config.credit_limits.default =
+                        flag_value(&mut args, &arg, "a positive amount", |s| {
+                            s.parse::<Amount>()
+                                .ok()
+                                .filter(|limit| !limit.is_negative())
+                        })
+                }
+                "--header-alias" => config.header_aliases.push(flag_value(
+                    &mut args,
+                    &arg,
+                    "<alias>=<column>, column being one of type, client, tx, amount or destination",
+                    parse_alias,
+                )),
+                "--no-headers" => config.header_row = HeaderRow::Absent,
+                "--format" => {
+                    output.format =
+                        flag_value(&mut args, &arg, "csv, json or jsonl", OutputFormat::new)
+                }
+                "--delimiter" => {
+                    output.delimiter =
+                        flag_value(&mut args, &arg, "a single character", parse_delimiter)
+                }
+                "--quote" => {
+                    output.quoting = flag_value(
+                        &mut args,
+                        &arg,
+                        "one of necessary, always, never or non-numeric",
+                        Quoting::new,
+                    )
+                }
+                "--legacy-output" => output.legacy = true,
+                "--tx-store" => {
+                    tx_store = Some(flag_value(&mut args, &arg, "a file path", |s| {
+                        Some(s.to_string())
+                    }))
+                }
+                "--tx-memory-budget" => {
+                    tx_memory_budget = flag_value(&mut args, &arg, "a size in MiB", |s| {
+                        s.parse::<usize>().ok()
+                    })
+                    .saturating_mul(MIB)
+                }
+                "--state" => {
+                    state = Some(flag_value(&mut args, &arg, "a file path", |s| {
+                        Some(s.to_string())
+                    }))
+                }
+                "--journal" => {
+                    journal = Some(flag_value(&mut args, &arg, "a file path", |s| {
+                        Some(s.to_string())
+                    }))
+                }
+                "--threads" => {
+                    threads = flag_value(&mut args, &arg, "a number of threads", |s| {
+                        s.parse::<usize>().ok().filter(|n| *n > 0)
+                    })
+                }
+                "--listen" if serve => {
+                    listen = Some(flag_value(&mut args, &arg, "an address", |s| {
+                        Some(s.to_string())
+                    }))
+                }
+                "--http" if serve => http = true,
+                "--merge-by" => {
+                    merge_by = Some(flag_value(&mut args, &arg, "a column name", |s| {
+                        Some(s.to_string())
+                    }))
+                }
+                _ if serve => exit_with_usage(&format!("Unexpected argument {}.", arg)),
+                _ => inputs.push(arg),
+            }
+        }
+
+        // The journal is emptied once a snapshot covers it, without one it
+        // would keep on growing and be replayed over unrelated inputs.
+        if journal.is_some() && state.is_none() {
+            exit_with_usage("--journal needs a --state snapshot to checkpoint into.")
+        }
+
+        // Rows reach the server one by one from many connections, neither
+        // the journal nor the threads apply.
+        if serve && (journal.is_some() || threads > 1) {
+            exit_with_usage("serve doesn't take --journal nor --threads.")
+        }
+
+        if inputs.len() > 1 && inputs.iter().any(|input| input == STDIN_PATH) {
+            exit_with_usage("The standard input can't be read along with files.")
+        }
+
+        // Merging takes the rows one at a time from every file.
+        if merge_by.is_some() && threads > 1 {
+            exit_with_usage("--merge-by processes the rows sequentially, without --threads.")
+        }
+
+        // The shards keep their transactions in memory, whatever the store.
+        if tx_store.is_some() && threads > 1 {
+            exit_with_usage("--tx-store keeps the transactions on disk, without --threads.")
+        }
+
+        if http && !cfg!(feature = "http") {
+            exit_with_usage("--http needs a build with the http feature.")
+        }
+
+        let command = match (serve, inputs.is_empty()) {
+            (true, _) => Command::Serve {
+                addr: listen.unwrap_or_else(|| DEFAULT_LISTEN.to_string()),
+                http,
+            },
+            (false, false) => Command::Process(inputs),
+            // No argument passed.
+            (false, true) => exit_with_usage(
+                "Please feed me with transactions files as command line arguments, \
+                 or '-' to read from the standard input.",
+            ),
+        };
+
+        Self {
+            command,
+            rejects,
+            credit_limits,
+            config,
+            output,
+            tx_store,
+            tx_memory_budget,
+            state,
+            journal,
+            threads,
+            merge_by,
+        }
+    }
+}
+
+/// Reads and parses the value following `flag`, exiting with a message
+/// telling what was expected if it's missing or invalid.
+fn flag_value<T>(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+    expected: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> T {
+    match args.next().as_deref().and_then(parse) {
+        Some(value) => value,
+        None => exit_with_usage(&format!("{} expects {}.", flag, expected)),
+    }
+}
+
+/// An alias reads `<alias>=<column>`, e.g. `transaction_id=tx`.
+fn parse_alias(s: &str) -> Option<(String, Column)> {
+    let (alias, column) = s.split_once('=')?;
+    Some((alias.to_string(), Column::new(column)?))
+}
+
+/// A delimiter is a single byte, `\t` standing for a tab.
+fn parse_delimiter(s: &str) -> Option<u8> {
+    match s.as_bytes() {
+        b"\\t" => Some(b'\t'),
+        [b] => Some(*b),
+        _ => None,
+    }
+}
+
+fn exit_with_usage(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    std::process::exit(1)
+}
 
 fn main() {
     // Command line handling part to end up with a path
     // to the transaction file.
-    let path_csv = {
-        let mut args = env::args();
-        match args.len() {
-            1 => {
-                // No argument passed.
-                eprintln!("Please feed me with a transactions file as command line argument.");
+    let mut args = Args::parse();
+
+    if let Some(path) = &args.credit_limits {
+        if let Err(e) = args.config.credit_limits.load(path) {
+            eprintln!("Failed to load the credit limits {}: {}.", path, e);
+            std::process::exit(1)
+        }
+    }
+
+    let mut engine = match &args.tx_store {
+        Some(path) => match DiskStorage::create(path, args.tx_memory_budget) {
+            Ok(store) => Engine::with_storage(args.config, Box::new(store)),
+            Err(e) => {
+                eprintln!("Failed to create the transaction store {}: {}.", path, e);
                 std::process::exit(1)
             }
-            2 => args.nth(1).expect("Fail to read command line argument."),
-            _ => {
-                // Too many argument entered.
-                eprintln!(
-                    "Please feed me with only one transactions file as command line argument."
-                );
+        },
+        None => Engine::with_config(args.config),
+    };
+    if let Some(path) = &args.rejects {
+        match RejectLog::create(path) {
+            Ok(log) => engine.set_reject_log(log),
+            Err(e) => {
+                eprintln!("Failed to create the rejects log {}: {}.", path, e);
+                std::process::exit(1)
+            }
+        }
+    }
+
+    if let Some(path) = &args.state {
+        if Path::new(path).exists() {
+            if let Err(e) = engine.load_state(path) {
+                eprintln!("Failed to load the state snapshot {}: {}.", path, e);
                 std::process::exit(1)
             }
         }
+    }
+
+    if let Some(path) = &args.journal {
+        let recovered =
+            Journal::open(path, DEFAULT_SYNC_EVERY).and_then(|journal| engine.set_journal(journal));
+        if let Err(e) = recovered {
+            eprintln!("Failed to recover from the journal {}: {}.", path, e);
+            std::process::exit(1)
+        }
+    }
+
+    let inputs = match &args.command {
+        Command::Process(inputs) => inputs,
+        Command::Serve { addr, http } => {
+            // Runs until killed, the snapshot if any is only resumed from.
+            let served = TcpListener::bind(addr).and_then(|listener| {
+                eprintln!("Listening on {}.", listener.local_addr()?);
+                if *http {
+                    serve_http(listener, engine)
+                } else {
+                    serve(listener, engine)
+                }
+            });
+            if let Err(e) = served {
+                eprintln!("Failed to serve on {}: {}.", addr, e);
+            }
+            std::process::exit(1)
+        }
     };
 
-    let mut engine = Engine::new();
-    match engine.process(&path_csv) {
-        Ok(_) => (),
+    let paths = match expand_inputs(inputs) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Failed to list the transactions files: {}.", e);
+            std::process::exit(1)
+        }
+    };
+    // A single file is processed as is, several get a summary each.
+    let processed = match (paths.as_slice(), &args.merge_by) {
+        ([path], None) if path.as_os_str() == STDIN_PATH => decompress(io::stdin().lock())
+            .map_err(EngineError::from)
+            .and_then(|input| engine.process_reader_parallel(input, args.threads))
+            .map(|_| Vec::new()),
+        ([path], None) => engine
+            .process_parallel(path, args.threads)
+            .map(|_| Vec::new()),
+        (paths, Some(column)) => engine.process_merged(paths, column),
+        (paths, None) => engine.process_files(paths, args.threads),
+    };
+    match processed {
+        Ok(summaries) => {
+            for summary in summaries {
+                eprintln!(
+                    "{}: {} rows, {} rejected.",
+                    summary.path.display(),
+                    summary.rows,
+                    summary.rejected
+                );
+            }
+        }
         Err(e) => {
             eprintln!("Engine failed with error : {}.", e);
             std::process::exit(1)
         }
     }
 
-    engine.print_db();
+    if let Some(path) = &args.state {
+        if let Err(e) = engine.save_state(path) {
+            eprintln!("Failed to save the state snapshot {}: {}.", path, e);
+            std::process::exit(1)
+        }
+    }
+
+    if let Err(e) = engine.write_db(io::stdout().lock(), &args.output) {
+        eprintln!("Failed to write the client accounts: {}.", e);
+        std::process::exit(1)
+    }
 }